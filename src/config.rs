@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+/// Name of the optional config file in the monorepo root.
+pub const CONFIG_FILE: &str = "dockyard.toml";
+
+/// Monorepo-wide dockyard settings. Every setting has a default, so the config
+/// file may be missing or partial.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Dir with vendored dependencies, relative to the monorepo root. A
+    /// shorthand for a single root named `third_party`, can't be used
+    /// together with `roots`.
+    pub third_party: Option<String>,
+    /// Named dirs with vendored dependencies, relative to the monorepo root.
+    /// Defaults to the single `third_party` root.
+    pub roots: BTreeMap<String, String>,
+    pub layout: LayoutConfig,
+    pub update: UpdateConfig,
+    pub upstream: UpstreamConfig,
+    pub templates: TemplatesConfig,
+}
+
+/// Names of the entries in a dependency dir.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutConfig {
+    /// Dir with the vendored upstream code.
+    pub repo: String,
+    /// Dir with patches applied on top of upstream.
+    pub patches: String,
+    /// Metadata file.
+    pub metadata: String,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig {
+            repo: "repo".to_string(),
+            patches: "patches".to_string(),
+            metadata: "dep_info.json".to_string(),
+        }
+    }
+}
+
+/// Defaults of `dockyard update`, command line options take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateConfig {
+    /// Commit updates to a dedicated `dockyard/update/<dep>-<version>` branch
+    /// instead of the current one.
+    pub branch: bool,
+    /// Squash the update commits into a single one.
+    pub squash: bool,
+    /// Stage the update instead of committing it.
+    pub no_commit: bool,
+}
+
+/// Restrictions of upstream sources.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Glob patterns of hosts dependencies may be vendored from, e.g.
+    /// `github.com` or `*.example.com`. Any host is allowed if it's empty.
+    /// Local paths and file:// urls are always allowed.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl UpstreamConfig {
+    /// Fails if `url` points to a host not in `allowed_hosts`.
+    pub fn check_url(&self, url: &str) -> Result<()> {
+        if self.allowed_hosts.is_empty() {
+            return Ok(());
+        }
+        let Some(host) = url_host(url) else {
+            return Ok(());
+        };
+
+        for pattern in self.allowed_hosts.iter() {
+            if glob::Pattern::new(pattern)?.matches(&host.to_ascii_lowercase()) {
+                return Ok(());
+            }
+        }
+        bail!(
+            "Host {} of {} isn't in upstream.allowed_hosts of {}",
+            host,
+            url,
+            CONFIG_FILE
+        );
+    }
+
+    fn validate(&self) -> Result<()> {
+        for pattern in self.allowed_hosts.iter() {
+            glob::Pattern::new(pattern)
+                .with_context(|| format!("Invalid upstream.allowed_hosts pattern {pattern:?}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Extracts host from `scheme://[user@]host[:port]/path` and scp-like
+/// `[user@]host:path` urls. Returns `None` for local paths and file:// urls.
+fn url_host(url: &str) -> Option<&str> {
+    let authority = if let Some((scheme, rest)) = url.split_once("://") {
+        if scheme == "file" {
+            return None;
+        }
+        rest.split('/').next().unwrap()
+    } else {
+        // scp-like syntax is recognized by git only if there is no slash
+        // before the first colon
+        let (authority, _) = url.split_once(':')?;
+        if authority.contains('/') {
+            return None;
+        }
+        authority
+    };
+    let host = authority.rsplit('@').next().unwrap();
+    let host = match host.strip_prefix('[') {
+        // IPv6 address
+        Some(host) => host.split(']').next().unwrap(),
+        None => host.split(':').next().unwrap(),
+    };
+
+    (!host.is_empty()).then_some(host)
+}
+
+/// Commit message templates of update events. `{name}` placeholders are
+/// replaced with values from `PLACEHOLDERS`, `{{` and `}}` produce literal
+/// braces.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplatesConfig {
+    /// New upstream code is imported.
+    pub import: String,
+    /// Patch is applied cleanly.
+    pub patch_applied: String,
+    /// Conflicted patch is resolved and refreshed.
+    pub conflict_resolved: String,
+    /// Conflicted patch is dropped.
+    pub patch_skipped: String,
+    /// Metadata is updated once all patches are applied.
+    pub metadata_updated: String,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        TemplatesConfig {
+            import: "Update {path} to {new_version}".to_string(),
+            patch_applied: "Applied patch ({index}/{count}) {patch} for {path}".to_string(),
+            conflict_resolved: "Resolve conflicted patch ({index}/{count}) {patch} for {path}"
+                .to_string(),
+            patch_skipped: "Skip patch ({index}/{count}) {patch} for {path}\n\n\
+                The patch doesn't apply to {new_version} and was dropped during the update."
+                .to_string(),
+            metadata_updated: "Update metadata for {path}".to_string(),
+        }
+    }
+}
+
+/// Placeholders available in commit message templates: dependency path, old
+/// and new upstream versions, patch name, its 1-based index and the number
+/// of patches. Patch placeholders are empty for events not related to a
+/// patch.
+pub const PLACEHOLDERS: &[&str] = &[
+    "path",
+    "old_version",
+    "new_version",
+    "patch",
+    "index",
+    "count",
+];
+
+/// Replaces `{name}` placeholders in `template` with values from `vars`.
+pub fn render_template(template: &str, vars: &[(&str, &str)]) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find(['{', '}']) {
+        result.push_str(&rest[..pos]);
+        let brace = &rest[pos..pos + 1];
+        rest = &rest[pos + 1..];
+        if rest.starts_with(brace) {
+            result.push_str(brace);
+            rest = &rest[1..];
+            continue;
+        }
+        if brace == "}" {
+            bail!("Unmatched }} in template {:?}", template);
+        }
+
+        let Some(end) = rest.find('}') else {
+            bail!("Unmatched {{ in template {:?}", template);
+        };
+        let name = &rest[..end];
+        let Some((_, value)) = vars.iter().find(|(var, _)| *var == name) else {
+            bail!(
+                "Unknown placeholder {{{}}} in template {:?}",
+                name,
+                template
+            );
+        };
+        result.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+impl TemplatesConfig {
+    fn validate(&self) -> Result<()> {
+        let vars: Vec<_> = PLACEHOLDERS.iter().map(|name| (*name, "")).collect();
+        for (event, template) in [
+            ("import", &self.import),
+            ("patch_applied", &self.patch_applied),
+            ("conflict_resolved", &self.conflict_resolved),
+            ("patch_skipped", &self.patch_skipped),
+            ("metadata_updated", &self.metadata_updated),
+        ] {
+            render_template(template, &vars)
+                .with_context(|| format!("Invalid templates.{event}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl LayoutConfig {
+    fn validate(&self) -> Result<()> {
+        let names = [
+            ("layout.repo", &self.repo),
+            ("layout.patches", &self.patches),
+            ("layout.metadata", &self.metadata),
+        ];
+        for (key, name) in names {
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                bail!("{} must be a file name, got {:?}", key, name);
+            }
+        }
+        if self.repo == self.patches || self.repo == self.metadata || self.patches == self.metadata
+        {
+            bail!("layout.repo, layout.patches and layout.metadata must differ");
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Loads the config from `root`. Returns the default config if there is
+    /// no config file.
+    pub fn load(root: &Path) -> Result<Config> {
+        let config_path = root.join(CONFIG_FILE);
+        if !config_path.exists() {
+            return Config::default().with_default_roots();
+        }
+
+        let content = fs::read_to_string(&config_path)
+            .with_context(|| format!("Failed to read {}", config_path.display()))?;
+        let config: Config = toml::from_str(&content)
+            .with_context(|| format!("Invalid {}", config_path.display()))?;
+        let config = config
+            .with_default_roots()
+            .with_context(|| format!("Invalid {}", config_path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid {}", config_path.display()))?;
+
+        Ok(config)
+    }
+
+    /// Turns `third_party` shorthand into `roots`.
+    fn with_default_roots(mut self) -> Result<Config> {
+        match self.third_party.take() {
+            Some(_) if !self.roots.is_empty() => {
+                bail!("third_party and roots can't be used together");
+            }
+            Some(third_party) => {
+                self.roots.insert("third_party".to_string(), third_party);
+            }
+            None if self.roots.is_empty() => {
+                self.roots
+                    .insert("third_party".to_string(), "third_party".to_string());
+            }
+            None => {}
+        }
+
+        Ok(self)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, path) in self.roots.iter() {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                bail!(
+                    "Root name must consist of letters, digits, _ and -, got {:?}",
+                    name
+                );
+            }
+            let root_path = Path::new(path);
+            if path.is_empty()
+                || root_path
+                    .components()
+                    .any(|c| !matches!(c, Component::Normal(_)))
+            {
+                bail!(
+                    "Root {} must be a relative path inside the monorepo, got {:?}",
+                    name,
+                    path
+                );
+            }
+            for (other_name, other_path) in self.roots.iter() {
+                if other_name != name && root_path.starts_with(other_path) {
+                    bail!(
+                        "Root {} ({}) is nested in root {} ({})",
+                        name,
+                        path,
+                        other_name,
+                        other_path
+                    );
+                }
+            }
+        }
+        if self.update.no_commit && (self.update.branch || self.update.squash) {
+            bail!("update.no_commit can't be combined with update.branch or update.squash");
+        }
+        self.layout.validate()?;
+        self.upstream.validate()?;
+        self.templates.validate()
+    }
+}
@@ -1,2 +1,4 @@
+pub mod config;
 pub mod paths;
+pub mod tree;
 pub mod utils;
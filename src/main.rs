@@ -1,11 +1,21 @@
 mod vendor;
 
+use std::{path::PathBuf, process::ExitCode};
+
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand, command};
-use dockyard::paths;
+use clap::{Parser, Subcommand, ValueEnum};
+use dockyard::paths::{self, DependencyArg};
 
 #[derive(Debug, Parser)]
 struct DockyardArgs {
+    #[arg(
+        long,
+        global = true,
+        help = " \
+        Monorepo root. If skipped then DOCKYARD_ROOT is used, otherwise the \
+        root is detected from the current dir."
+    )]
+    root: Option<PathBuf>,
     #[command(subcommand)]
     command: Command,
 }
@@ -18,40 +28,135 @@ enum Command {
     Vendor(VendorCommandArgs),
     #[command(about = "Extract patch for third-party dependency to //third_party/dep_name/patches")]
     ExtractPatch(ExtractPatchCommandArgs),
+    #[command(about = "List vendored dependencies under //third_party")]
+    List(ListCommandArgs),
+    #[command(about = "Check which vendored dependencies lag behind upstream")]
+    Outdated(OutdatedCommandArgs),
+    #[command(about = "Verify vendored code matches upstream with applied patches")]
+    Verify(VerifyCommandArgs),
+    #[command(about = "Reorder, drop, rename, squash or edit patches of vendored dependency")]
+    Patch(PatchCommandArgs),
+    #[command(about = "Work on patches as git commits on top of upstream history")]
+    Workspace(WorkspaceCommandArgs),
 }
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Parser)]
 struct VendorCommandArgs {
     #[arg(
         long,
+        required_unless_present = "archive",
+        conflicts_with = "archive",
         help = " \
         Git repository url to import into monorepository."
     )]
-    git: String,
+    git: Option<String>,
     #[arg(
         long,
+        requires = "sha256",
+        help = " \
+        Release archive (.tar.gz, .tar.xz, .tar.bz2 or .zip) to import into \
+        monorepository. Either url or local path."
+    )]
+    archive: Option<String>,
+    #[arg(
+        long,
+        requires = "archive",
+        help = " \
+        Expected SHA-256 checksum of the archive."
+    )]
+    sha256: Option<String>,
+    #[arg(
+        long,
+        requires = "archive",
+        help = " \
+        Strip the number of leading path components from archive entries, \
+        like tar --strip-components."
+    )]
+    strip_components: Option<u32>,
+    #[arg(
+        long,
+        conflicts_with = "archive",
         help = " \
         Tag or commit hash to import. If skipped then the default branch will \
         be imported."
     )]
     version: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "archive",
+        help = " \
+        Glob pattern of release tags, e.g. 'v*'. If provided, update and \
+        outdated follow the newest matching tag instead of a branch."
+    )]
+    tag_pattern: Option<String>,
+    #[arg(
+        long,
+        help = " \
+        Import only the subdir of the upstream tree, e.g. libfoo. Update and \
+        verify use the same subdir."
+    )]
+    subdir: Option<String>,
+    #[arg(
+        long,
+        help = " \
+        Glob pattern of files to vendor, e.g. 'src' or 'include/**/*.h'. \
+        Can be repeated. If skipped then all files are vendored."
+    )]
+    include: Vec<String>,
     #[arg(
         long,
         help = " \
-        Path where to import dependency. \
-        The path must be provided in the canonical format: //third_party/dep_name"
+        Glob pattern of files to remove after fetching upstream, e.g. 'tests' \
+        or '**/*.png'. Can be repeated."
     )]
-    path: String,
+    exclude: Vec<String>,
+    #[arg(
+        long,
+        help = " \
+        Path where to import dependency, either in the canonical format \
+        //third_party/dep_name or relative to the current dir."
+    )]
+    path: DependencyArg,
 }
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Default, Parser)]
 struct UpdateCommandArgs {
     #[arg(
         long,
+        conflicts_with = "archive",
         help = " \
         Tag or commit hash to import. If skipped then HEAD will be used."
     )]
     version: Option<String>,
+    #[arg(
+        long,
+        requires = "sha256",
+        help = " \
+        New release archive for dependency vendored from archive. \
+        Either url or local path."
+    )]
+    archive: Option<String>,
+    #[arg(
+        long,
+        requires = "archive",
+        help = " \
+        Expected SHA-256 checksum of the new archive."
+    )]
+    sha256: Option<String>,
+    #[arg(
+        long,
+        requires = "archive",
+        help = " \
+        Strip the number of leading path components from archive entries. \
+        If skipped then the previous value is used."
+    )]
+    strip_components: Option<u32>,
     #[arg(
         long,
         help = " \
@@ -73,6 +178,51 @@ struct UpdateCommandArgs {
         default_value_t = false
     )]
     cont: bool,
+    #[arg(
+        long = "skip",
+        help = " \
+        Skip the conflicted patch, remove it from the patches and continue \
+        with subsequent patches.",
+        default_value_t = false
+    )]
+    skip: bool,
+    #[arg(
+        long,
+        overrides_with = "no_branch",
+        help = " \
+        Commit the update to a new branch dockyard/update/<dep>-<version> \
+        created from the current commit. Defaults to update.branch in \
+        dockyard.toml.",
+        default_value_t = false
+    )]
+    branch: bool,
+    #[arg(
+        long,
+        overrides_with = "branch",
+        help = " \
+        Commit the update to the current branch even if dockyard.toml sets \
+        update.branch.",
+        default_value_t = false
+    )]
+    no_branch: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["status", "cont", "skip", "abort"],
+        help = " \
+        Squash all commits of the update into a single commit once all \
+        patches are applied.",
+        default_value_t = false
+    )]
+    squash: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["status", "cont", "skip", "abort", "squash", "branch"],
+        help = " \
+        Stage every step of the update instead of committing it. The update \
+        is left staged for review.",
+        default_value_t = false
+    )]
+    no_commit: bool,
     #[arg(
         long = "abort",
         help = " \
@@ -81,30 +231,252 @@ struct UpdateCommandArgs {
     )]
     abort: bool,
     #[arg(help = " \
-        Update third party dependency under specified path. \
-        The path must be provided in the canonical format: //third_party/dep_name")]
-    path: Option<String>,
+        Dependency to update: canonical path //third_party/dep_name, path \
+        relative to the current dir or dependency name. If skipped then the \
+        dependency the current dir is in is updated.")]
+    path: Option<DependencyArg>,
 }
 
-#[derive(Debug, Parser)]
+#[derive(Debug, Default, Parser)]
 struct ExtractPatchCommandArgs {
     #[arg(
         long,
         help = " \
-        Extracts patch from changes in third_party code. \
-        The path must be provided in the canonical format: //third_party/dep_name"
+        Extracts patch from changes in third_party code. Either canonical \
+        path //third_party/dep_name, path relative to the current dir or \
+        dependency name. If skipped then the dependency the current dir is \
+        in is used."
     )]
-    path: String,
+    path: Option<DependencyArg>,
+    #[arg(
+        long,
+        help = " \
+        Patch title, e.g. 'Fix build with musl'. The title is slugified into \
+        the patch file name and written to the patch header as the subject."
+    )]
+    name: Option<String>,
+    #[arg(
+        long,
+        help = " \
+        Patch description written to the patch header. If --name is skipped \
+        then the first line of the message is used as the title."
+    )]
+    message: Option<String>,
 }
 
-fn main() -> Result<()> {
+#[derive(Debug, Parser)]
+struct ListCommandArgs {
+    #[arg(
+        long,
+        value_enum,
+        help = " \
+        Output format.",
+        default_value_t = OutputFormat::Text
+    )]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Parser)]
+struct OutdatedCommandArgs {
+    #[arg(
+        long,
+        value_enum,
+        help = " \
+        Output format.",
+        default_value_t = OutputFormat::Text
+    )]
+    format: OutputFormat,
+    #[arg(help = " \
+        Check only the dependency under specified path. Either canonical path \
+        //third_party/dep_name, path relative to the current dir or \
+        dependency name.")]
+    path: Option<DependencyArg>,
+}
+
+#[derive(Debug, Parser)]
+struct VerifyCommandArgs {
+    #[arg(
+        long,
+        help = " \
+        Compare vendored code with the tree hash recorded in metadata instead \
+        of fetching upstream. Detects changes not recorded in patches, but \
+        can't show which files drift.",
+        default_value_t = false
+    )]
+    offline: bool,
+    #[arg(help = " \
+        Verify only the dependency under specified path. If skipped then all \
+        dependencies are verified. Either canonical path \
+        //third_party/dep_name, path relative to the current dir or \
+        dependency name.")]
+    path: Option<DependencyArg>,
+}
+
+#[derive(Debug, Parser)]
+struct PatchCommandArgs {
+    #[arg(
+        long,
+        global = true,
+        help = " \
+        Dependency whose patches to manage. Either canonical path \
+        //third_party/dep_name, path relative to the current dir or \
+        dependency name. If skipped then the dependency the current dir is \
+        in is used."
+    )]
+    path: Option<DependencyArg>,
+    #[command(subcommand)]
+    command: PatchCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum PatchCommand {
+    #[command(about = "List patches in the order they are applied")]
+    List,
+    #[command(about = "Remove patch and revert its changes in vendored code")]
+    Drop(PatchDropArgs),
+    #[command(about = "Move patch to another position in the series")]
+    Move(PatchMoveArgs),
+    #[command(about = "Rename patch and update its subject")]
+    Rename(PatchRenameArgs),
+    #[command(about = "Fold one patch into another")]
+    Squash(PatchSquashArgs),
+    #[command(about = "Edit patch in place and rebase subsequent patches onto it")]
+    Edit(PatchEditArgs),
+}
+
+#[derive(Debug, Parser)]
+struct PatchDropArgs {
+    #[arg(help = " \
+        Patch to drop, either its position as shown by `dockyard patch list` \
+        or file name.")]
+    patch: String,
+}
+
+#[derive(Debug, Parser)]
+struct PatchMoveArgs {
+    #[arg(help = " \
+        Patch to move, either its position as shown by `dockyard patch list` \
+        or file name.")]
+    patch: String,
+    #[arg(help = " \
+        New position of the patch, starting from 1.")]
+    position: usize,
+}
+
+#[derive(Debug, Parser)]
+struct PatchRenameArgs {
+    #[arg(help = " \
+        Patch to rename, either its position as shown by `dockyard patch list` \
+        or file name.")]
+    patch: String,
+    #[arg(help = " \
+        New patch title, e.g. 'Fix build with musl'. The title is slugified \
+        into the patch file name.")]
+    name: String,
+}
+
+#[derive(Debug, Parser)]
+struct PatchSquashArgs {
+    #[arg(help = " \
+        Patch to fold the other one into. The squashed patch keeps its name, \
+        header and position.")]
+    patch: String,
+    #[arg(help = " \
+        Patch to fold, it's removed from the series.")]
+    other: String,
+}
+
+#[derive(Debug, Parser)]
+struct PatchEditArgs {
+    #[arg(
+        required_unless_present = "finish",
+        help = " \
+        Patch to edit, either its position as shown by `dockyard patch list` \
+        or file name. Vendored code is reset to upstream with the patches up \
+        to this one applied."
+    )]
+    patch: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "patch",
+        help = " \
+        Regenerate the edited patch from changes in vendored code and rebase \
+        subsequent patches onto it.",
+        default_value_t = false
+    )]
+    finish: bool,
+}
+
+#[derive(Debug, Parser)]
+struct WorkspaceCommandArgs {
+    #[command(subcommand)]
+    command: WorkspaceCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum WorkspaceCommand {
+    #[command(about = "Create git repo with upstream history and a commit per patch")]
+    Open(WorkspaceOpenArgs),
+    #[command(about = "Turn workspace commits back into patches and vendored code")]
+    Sync(WorkspaceSyncArgs),
+}
+
+#[derive(Debug, Default, Parser)]
+struct WorkspaceOpenArgs {
+    #[arg(
+        long,
+        help = " \
+        Dir to create the workspace in, it must be empty or not exist. If \
+        skipped then a new temporary dir is used."
+    )]
+    dir: Option<PathBuf>,
+    #[arg(help = " \
+        Dependency to open: canonical path //third_party/dep_name, path \
+        relative to the current dir or dependency name. If skipped then the \
+        dependency the current dir is in is used.")]
+    path: Option<DependencyArg>,
+}
+
+#[derive(Debug, Default, Parser)]
+struct WorkspaceSyncArgs {
+    #[arg(help = " \
+        Workspace dir. If skipped then the workspace the current dir is in is \
+        synced.")]
+    dir: Option<PathBuf>,
+}
+
+/// Exit code of `dockyard outdated` if some dependencies lag behind upstream.
+const OUTDATED_EXIT_CODE: u8 = 2;
+
+fn main() -> Result<ExitCode> {
     let args = DockyardArgs::parse();
 
-    let paths = paths::MonorepoPaths::new().context("Could not find monorepo checkout paths")?;
+    let mut root = args.root;
+    if root.is_none()
+        && let Command::Workspace(WorkspaceCommandArgs {
+            command: WorkspaceCommand::Sync(ref sync_args),
+        }) = args.command
+    {
+        // Workspaces are outside of the monorepo, the root is recorded in them
+        root = vendor::workspace_root(sync_args.dir.as_deref())?;
+    }
+    let paths = paths::MonorepoPaths::new(root.as_deref())
+        .context("Could not load monorepo checkout paths and config")?;
 
     match args.command {
-        Command::Update(args) => vendor::update(args, &paths),
-        Command::Vendor(args) => vendor::vendor(args, &paths),
-        Command::ExtractPatch(args) => vendor::extract_patch(args, &paths),
+        Command::Update(args) => vendor::update(args, &paths)?,
+        Command::Vendor(args) => vendor::vendor(args, &paths)?,
+        Command::ExtractPatch(args) => vendor::extract_patch(args, &paths)?,
+        Command::List(args) => vendor::list(args, &paths)?,
+        Command::Verify(args) => vendor::verify(args, &paths)?,
+        Command::Patch(args) => vendor::patch(args, &paths)?,
+        Command::Workspace(args) => vendor::workspace(args, &paths)?,
+        Command::Outdated(args) => {
+            if !vendor::outdated(args, &paths)? {
+                return Ok(ExitCode::from(OUTDATED_EXIT_CODE));
+            }
+        }
     }
+
+    Ok(ExitCode::SUCCESS)
 }
@@ -1,50 +1,347 @@
 use std::{
-    env, io,
-    path::{Path, PathBuf},
+    env,
+    ffi::OsStr,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
 };
 
+use anyhow::{anyhow, bail};
+
+use crate::config::{CONFIG_FILE, Config};
+
+/// Environment variable with the monorepo root, overrides root detection.
+pub const ROOT_ENV: &str = "DOCKYARD_ROOT";
+
+/// VCS dirs and files that mark the monorepo root if there is no
+/// `dockyard.toml` above the current dir. The nearest one wins.
+const VCS_ROOT_MARKERS: &[&str] = &[".git", ".sl", ".jj"];
+
+/// Bazel files marking the monorepo root if there is no VCS marker either.
+/// Vendored Bazel projects ship them too, so they are weaker than VCS ones.
+const BUILD_ROOT_MARKERS: &[&str] = &["WORKSPACE", "WORKSPACE.bazel"];
+
 /// Monorepo source tree paths. All members other than `root` are relative to
 /// `root`.
 pub struct MonorepoPaths {
     /// The monorepo checkout root, as an absolute path.
     pub root: PathBuf,
 
-    /// The third_party directory.
-    pub third_party: PathBuf,
+    /// The third-party roots, dirs with vendored dependencies.
+    pub roots: Vec<ThirdPartyRoot>,
+
+    /// Settings from `dockyard.toml` in the root.
+    pub config: Config,
+}
+
+/// Named dir with vendored dependencies, e.g. `//third_party`.
+pub struct ThirdPartyRoot {
+    pub name: String,
+    pub path: PathBuf,
 }
 
 impl MonorepoPaths {
     /// Create the `MonorepoPath` resolver. Accesses the filesystem to get the
-    /// checkout root.
-    pub fn new() -> io::Result<MonorepoPaths> {
-        let root_dir = find_repo_root()?;
+    /// checkout root and its config. `root` given explicitly, e.g. with
+    /// `--root`, takes precedence over the detected one.
+    pub fn new(root: Option<&Path>) -> anyhow::Result<MonorepoPaths> {
+        let root_dir = match root {
+            Some(root) => resolve_repo_root(Some(root), None, &env::current_dir()?)?,
+            None => find_repo_root()?,
+        };
 
-        Ok(MonorepoPaths {
-            root: root_dir.clone(),
-            third_party: check_path(&root_dir, THIRD_PARTY_DIR)?,
-        })
+        MonorepoPaths::from_dir(&root_dir)
+    }
+
+    /// Create the `MonorepoPath` resolver from provided root dir.
+    pub fn from_dir(path: &Path) -> anyhow::Result<MonorepoPaths> {
+        let root_dir = path.to_path_buf();
+        let config = Config::load(&root_dir)?;
+
+        MonorepoPaths::with_config(root_dir, config)
     }
 
-    /// Create the `MonorepoPath` resolver with custom 3rd party path. Accesses the filesystem to
-    /// get the checkout root.
-    pub fn from_third_party_dir(third_party_path: &str) -> io::Result<MonorepoPaths> {
-        let root_dir = find_repo_root()?;
+    fn with_config(root_dir: PathBuf, config: Config) -> anyhow::Result<MonorepoPaths> {
+        let roots = config
+            .roots
+            .iter()
+            .map(|(name, path)| {
+                Ok(ThirdPartyRoot {
+                    name: name.clone(),
+                    path: check_path(&root_dir, path)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
 
         Ok(MonorepoPaths {
-            root: root_dir.clone(),
-            third_party: check_path(&root_dir, third_party_path)?,
+            root: root_dir,
+            roots,
+            config,
         })
     }
 
-    /// Create the `MonorepoPath` resolver from provided root dir.
-    pub fn from_dir(path: &Path) -> io::Result<MonorepoPaths> {
-        let root_dir = path.to_path_buf();
+    /// Returns the third-party root `path` is under.
+    pub fn root_of(&self, path: &Path) -> Option<&ThirdPartyRoot> {
+        self.roots.iter().find(|root| path.starts_with(&root.path))
+    }
 
-        Ok(MonorepoPaths {
-            root: root_dir.clone(),
-            third_party: check_path(&root_dir, THIRD_PARTY_DIR)?,
-        })
+    /// Dir with the vendored upstream code of the dependency in `target_dir`.
+    pub fn repo_dir(&self, target_dir: &Path) -> PathBuf {
+        target_dir.join(&self.config.layout.repo)
+    }
+
+    /// Dir with patches of the dependency in `target_dir`.
+    pub fn patches_dir(&self, target_dir: &Path) -> PathBuf {
+        target_dir.join(&self.config.layout.patches)
+    }
+
+    /// Metadata file of the dependency in `target_dir`.
+    pub fn metadata_file(&self, target_dir: &Path) -> PathBuf {
+        target_dir.join(&self.config.layout.metadata)
     }
+
+    /// Returns dirs with dependency metadata under all third-party roots.
+    pub fn find_dependencies(&self) -> io::Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for root in self.roots.iter() {
+            dirs.extend(find_dependencies(&root.path, &self.config.layout.metadata)?);
+        }
+        dirs.sort();
+
+        Ok(dirs)
+    }
+
+    /// Resolves an existing dependency from the command line relative to the
+    /// current dir. Without `arg` looks for the nearest dependency up from the
+    /// current dir.
+    pub fn resolve_dependency(&self, arg: Option<&DependencyArg>) -> anyhow::Result<CanonicalPath> {
+        self.resolve_dependency_from(arg, &env::current_dir()?)
+    }
+
+    /// Resolves an existing dependency from the command line relative to
+    /// `cwd`. See [`MonorepoPaths::resolve_dependency`].
+    pub fn resolve_dependency_from(
+        &self,
+        arg: Option<&DependencyArg>,
+        cwd: &Path,
+    ) -> anyhow::Result<CanonicalPath> {
+        let path = match arg {
+            None => {
+                return self.enclosing_dependency(cwd)?.ok_or_else(|| {
+                    anyhow!(
+                        "{} isn't inside of a vendored dependency, provide the dependency path",
+                        cwd.display()
+                    )
+                });
+            }
+            Some(DependencyArg::Canonical(path)) => path.clone(),
+            Some(DependencyArg::Path(path)) => {
+                let abs_path = normalize(&cwd.join(path));
+                return self.enclosing_dependency(&abs_path)?.ok_or_else(|| {
+                    anyhow!("{} isn't inside of a vendored dependency", path.display())
+                });
+            }
+            Some(DependencyArg::Name(name)) => {
+                // A dir in the current one takes precedence over the name.
+                let abs_path = cwd.join(name);
+                if abs_path.exists()
+                    && self.in_monorepo(&abs_path).is_some()
+                    && let Some(path) = self.enclosing_dependency(&abs_path)?
+                {
+                    return Ok(path);
+                }
+                return self.find_dependency_by_name(name);
+            }
+        };
+
+        let target_dir = path.to_abs(self)?;
+        if !self.metadata_file(&target_dir).is_file() {
+            bail!(
+                "Dependency not found: {}{}",
+                path,
+                self.close_matches(path.name().unwrap_or_default())?
+            );
+        }
+
+        Ok(path)
+    }
+
+    /// Resolves the path of a dependency to be vendored from the command
+    /// line. Non-canonical paths are relative to the current dir.
+    pub fn resolve_new_dependency(&self, arg: &DependencyArg) -> anyhow::Result<CanonicalPath> {
+        self.resolve_new_dependency_from(arg, &env::current_dir()?)
+    }
+
+    /// Resolves the path of a dependency to be vendored relative to `cwd`.
+    pub fn resolve_new_dependency_from(
+        &self,
+        arg: &DependencyArg,
+        cwd: &Path,
+    ) -> anyhow::Result<CanonicalPath> {
+        let from_cwd = |path: &Path| {
+            let abs_path = cwd.join(path);
+            let abs_path = self
+                .in_monorepo(&abs_path)
+                .unwrap_or_else(|| normalize(&abs_path));
+            CanonicalPath::from_abs(self, &abs_path)
+        };
+        let path = match arg {
+            DependencyArg::Canonical(path) => path.clone(),
+            DependencyArg::Path(path) => from_cwd(path)?,
+            DependencyArg::Name(name) => from_cwd(Path::new(name))?,
+        };
+        path.to_abs(self)?;
+
+        Ok(path)
+    }
+
+    /// Converts absolute `path` to the one under the monorepo root as it's
+    /// spelled in `root`. Paths reaching the monorepo through a symlink, or a
+    /// root given through one, are matched by their canonical form. `None` if
+    /// the path is outside of the monorepo.
+    fn in_monorepo(&self, path: &Path) -> Option<PathBuf> {
+        let path = normalize(path);
+        if path.starts_with(&self.root) {
+            return Some(path);
+        }
+
+        // Only the existing part of the path can be canonicalized
+        let existing = path.ancestors().find(|dir| dir.exists())?;
+        let canonical_root = fs::canonicalize(&self.root).ok()?;
+        let canonical_path = fs::canonicalize(existing).ok()?;
+        let relative_path = canonical_path.strip_prefix(&canonical_root).ok()?;
+
+        Some(
+            self.root
+                .join(relative_path)
+                .join(path.strip_prefix(existing).ok()?),
+        )
+    }
+
+    /// Returns the dependency `path` is inside of.
+    fn enclosing_dependency(&self, path: &Path) -> anyhow::Result<Option<CanonicalPath>> {
+        let Some(path) = self.in_monorepo(path) else {
+            bail!(
+                "{} is outside of monorepo {}",
+                path.display(),
+                self.root.display()
+            );
+        };
+
+        let Some(root) = self.root_of(&path) else {
+            return Ok(None);
+        };
+        for dir in path.ancestors().take_while(|dir| *dir != root.path) {
+            if self.metadata_file(dir).is_file() {
+                return Ok(Some(CanonicalPath::from_abs(self, dir)?));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn find_dependency_by_name(&self, name: &str) -> anyhow::Result<CanonicalPath> {
+        let mut matches = Vec::new();
+        for dir in self.find_dependencies()? {
+            let path = CanonicalPath::from_abs(self, &dir)?;
+            if path.name() == Some(name) {
+                matches.push(path);
+            }
+        }
+
+        match matches.len() {
+            0 => bail!("Unknown dependency {}{}", name, self.close_matches(name)?),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                let matches: Vec<_> = matches.iter().map(|path| path.to_string()).collect();
+                bail!(
+                    "Dependency name {} is ambiguous, provide one of: {}",
+                    name,
+                    matches.join(", ")
+                )
+            }
+        }
+    }
+
+    /// Suggests vendored dependencies with names similar to `name`.
+    fn close_matches(&self, name: &str) -> io::Result<String> {
+        let max_distance = (name.chars().count() / 3).max(1);
+        let mut matches = Vec::new();
+        for dir in self.find_dependencies()? {
+            let path = CanonicalPath::from_abs(self, &dir)?;
+            let dep_name = path.name().unwrap_or_default();
+            if dep_name.contains(name)
+                || name.contains(dep_name)
+                || edit_distance(dep_name, name) <= max_distance
+            {
+                matches.push(path.to_string());
+            }
+        }
+
+        if matches.is_empty() {
+            Ok(String::new())
+        } else {
+            Ok(format!(", did you mean: {}", matches.join(", ")))
+        }
+    }
+}
+
+/// Recursively looks for dirs with dependency metadata. Vendored dependencies
+/// may be nested, e.g. //third_party/org/name, but never inside of another
+/// dependency.
+fn find_dependencies(dir: &Path, metadata_file: &str) -> io::Result<Vec<PathBuf>> {
+    if dir.join(metadata_file).is_file() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            subdirs.push(entry.path());
+        }
+    }
+    subdirs.sort();
+
+    let mut deps = Vec::new();
+    for subdir in subdirs {
+        deps.extend(find_dependencies(&subdir, metadata_file)?);
+    }
+
+    Ok(deps)
+}
+
+/// Resolves `.` and `..` components without touching the filesystem, so
+/// symlinks in the monorepo are kept as is.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
 }
 
 fn check_path(root: &Path, p_str: &str) -> io::Result<PathBuf> {
@@ -61,30 +358,549 @@ fn check_path(root: &Path, p_str: &str) -> io::Result<PathBuf> {
     Ok(full_path)
 }
 
-/// Traverse up the directory tree to find the monorepo root (contains `.git`)
+/// Finds the monorepo root for the current dir. `DOCKYARD_ROOT` env variable
+/// takes precedence over detection.
 pub fn find_repo_root() -> io::Result<PathBuf> {
-    let mut current = env::current_dir()?;
+    resolve_repo_root(None, env::var_os(ROOT_ENV).as_deref(), &env::current_dir()?)
+}
+
+/// Finds the monorepo root. The explicit `root` takes precedence over
+/// `env_root`, relative ones are resolved against `cwd`. Otherwise traverses up
+/// from `cwd` to the topmost dir with `dockyard.toml` or, if there is none, to
+/// the nearest one with a VCS dir or `.git` file of a worktree or submodule,
+/// and finally to the nearest one with a Bazel `WORKSPACE`.
+pub fn resolve_repo_root(
+    root: Option<&Path>,
+    env_root: Option<&OsStr>,
+    cwd: &Path,
+) -> io::Result<PathBuf> {
+    let explicit_root = root.or(env_root.filter(|r| !r.is_empty()).map(Path::new));
+    if let Some(root) = explicit_root {
+        let root = cwd.join(root);
+        if !root.is_dir() {
+            return Err(io::Error::other(format!(
+                "monorepo root {} isn't a dir",
+                root.display()
+            )));
+        }
+        return fs::canonicalize(root);
+    }
+
+    if let Some(root) = cwd
+        .ancestors()
+        .filter(|dir| dir.join(CONFIG_FILE).is_file())
+        .last()
+    {
+        return Ok(root.to_path_buf());
+    }
+
+    let find_marked = |markers: &[&str]| {
+        cwd.ancestors().find(|dir| {
+            markers
+                .iter()
+                .any(|marker| is_root_marker(&dir.join(marker)))
+        })
+    };
+    find_marked(VCS_ROOT_MARKERS)
+        .or_else(|| find_marked(BUILD_ROOT_MARKERS))
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            io::Error::other(format!(
+                "could not find monorepo root (invoked from monorepo?), \
+                 use --root or {ROOT_ENV} to set it"
+            ))
+        })
+}
+
+/// `.git` may be a file pointing to the git dir in worktrees and submodules.
+fn is_root_marker(path: &Path) -> bool {
+    if path.file_name() == Some(OsStr::new(".git")) && path.is_file() {
+        return fs::read_to_string(path).is_ok_and(|content| content.starts_with("gitdir:"));
+    }
+
+    path.exists()
+}
 
-    loop {
-        if current.join(".git").is_dir() {
-            return Ok(current);
+/// Canonical path of a dependency, e.g. //third_party/dep_name. Components
+/// are relative to the monorepo root and can't be empty, `.` or `..`, so the
+/// path never escapes the monorepo. The default is the monorepo root `//`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CanonicalPath {
+    /// Normalized path without a trailing slash.
+    path: String,
+}
+
+impl CanonicalPath {
+    /// Converts absolute path under the monorepo root to the canonical format.
+    pub fn from_abs(paths: &MonorepoPaths, path: &Path) -> io::Result<CanonicalPath> {
+        let relative_path = path.strip_prefix(&paths.root).map_err(|_| {
+            io::Error::other(format!(
+                "{} is outside of monorepo {}",
+                path.display(),
+                paths.root.display()
+            ))
+        })?;
+
+        let mut components = Vec::new();
+        for component in relative_path.components() {
+            match component {
+                Component::Normal(name) => components.push(name.to_str().ok_or_else(|| {
+                    io::Error::other(format!("{} isn't valid UTF-8", path.display()))
+                })?),
+                _ => {
+                    return Err(io::Error::other(format!(
+                        "{} isn't a normalized path",
+                        path.display()
+                    )));
+                }
+            }
+        }
+
+        format!("//{}", components.join("/")).parse()
+    }
+
+    /// Converts the path to absolute one. The path must be under one of the
+    /// third-party roots and can't be the root itself.
+    pub fn to_abs(&self, paths: &MonorepoPaths) -> io::Result<PathBuf> {
+        let abs_path = self.components().fold(paths.root.clone(), |p, c| p.join(c));
+        match paths.root_of(&abs_path) {
+            Some(root) if abs_path != root.path => Ok(abs_path),
+            _ => {
+                let roots: Vec<_> = paths
+                    .config
+                    .roots
+                    .values()
+                    .map(|root| format!("//{root}"))
+                    .collect();
+                Err(io::Error::other(format!(
+                    "{} isn't under any third-party root: {}",
+                    self,
+                    roots.join(", ")
+                )))
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        if self.path.is_empty() {
+            "//"
+        } else {
+            &self.path
+        }
+    }
+
+    /// Name of the last component, e.g. dep_name for //third_party/dep_name.
+    pub fn name(&self) -> Option<&str> {
+        self.components().last()
+    }
+
+    fn components(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/').skip(2)
+    }
+}
+
+impl FromStr for CanonicalPath {
+    type Err = io::Error;
+
+    fn from_str(path: &str) -> io::Result<CanonicalPath> {
+        let Some(relative_path) = path.strip_prefix("//") else {
+            return Err(io::Error::other(format!(
+                "Monorepo canonical path must start with //, got {path}"
+            )));
+        };
+
+        let relative_path = relative_path.strip_suffix('/').unwrap_or(relative_path);
+        if relative_path.is_empty() {
+            return Ok(CanonicalPath::default());
         }
-        if !current.pop() {
-            return Err(io::Error::other(
-                "could not find monorepo root (invoked from monorepo?)",
-            ));
+        for component in relative_path.split('/') {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(io::Error::other(format!(
+                    "Invalid component {component:?} in canonical path {path}"
+                )));
+            }
+            if component.contains('\\') {
+                return Err(io::Error::other(format!(
+                    "Canonical path {path} must use / as separator"
+                )));
+            }
         }
+
+        Ok(CanonicalPath {
+            path: format!("//{relative_path}"),
+        })
     }
 }
 
-pub fn path_to_abs(paths: &MonorepoPaths, path: &str) -> io::Result<PathBuf> {
-    if !path.starts_with("//") {
-        return Err(io::Error::other(
-            "Monorepo canonical path must start with //",
-        ));
+impl fmt::Display for CanonicalPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
+}
 
-    Ok(paths.root.join(&path[2..]))
+/// Dependency as given on the command line: a canonical path, a filesystem
+/// path relative to the current dir or a bare dependency name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyArg {
+    Canonical(CanonicalPath),
+    Path(PathBuf),
+    Name(String),
 }
 
-static THIRD_PARTY_DIR: &str = "third_party";
+impl Default for DependencyArg {
+    fn default() -> Self {
+        DependencyArg::Canonical(CanonicalPath::default())
+    }
+}
+
+impl FromStr for DependencyArg {
+    type Err = io::Error;
+
+    fn from_str(arg: &str) -> io::Result<DependencyArg> {
+        if arg.starts_with("//") {
+            Ok(DependencyArg::Canonical(arg.parse()?))
+        } else if arg.is_empty() {
+            Err(io::Error::other("Dependency path can't be empty"))
+        } else if arg.contains('/') || arg == "." || arg == ".." {
+            Ok(DependencyArg::Path(PathBuf::from(arg)))
+        } else {
+            Ok(DependencyArg::Name(arg.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for DependencyArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyArg::Canonical(path) => path.fmt(f),
+            DependencyArg::Path(path) => path.display().fmt(f),
+            DependencyArg::Name(name) => name.fmt(f),
+        }
+    }
+}
+
+/// Converts canonical path of a dependency, e.g. //third_party/dep_name, to
+/// absolute one. The path must be under one of the third-party roots.
+pub fn path_to_abs(paths: &MonorepoPaths, path: &str) -> io::Result<PathBuf> {
+    path.parse::<CanonicalPath>()?.to_abs(paths)
+}
+
+/// Converts absolute path under the monorepo root to the canonical format:
+/// //third_party/dep_name
+pub fn abs_to_path(paths: &MonorepoPaths, path: &Path) -> io::Result<String> {
+    Ok(CanonicalPath::from_abs(paths, path)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use tempfile::{TempDir, tempdir};
+
+    use super::*;
+
+    /// Creates a monorepo dir with the default `third_party` root.
+    fn create_monorepo() -> anyhow::Result<TempDir> {
+        let temp_dir = tempdir()?;
+        fs::create_dir_all(temp_dir.path().join("third_party"))?;
+
+        Ok(temp_dir)
+    }
+
+    #[test]
+    fn test_canonical_path() -> anyhow::Result<()> {
+        let temp_dir = create_monorepo()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let path: CanonicalPath = "//third_party/org/example/".parse()?;
+        assert_eq!(path.as_str(), "//third_party/org/example");
+        assert_eq!(path.name(), Some("example"));
+        let target_dir = path.to_abs(&paths)?;
+        assert_eq!(target_dir, temp_dir.path().join("third_party/org/example"));
+        assert_eq!(CanonicalPath::from_abs(&paths, &target_dir)?, path);
+        assert_eq!(CanonicalPath::default().as_str(), "//");
+
+        for invalid_path in [
+            "third_party/example",
+            "/third_party/example",
+            "//../etc",
+            "//third_party/../src",
+            "//third_party/./example",
+            "//third_party//example",
+            "///third_party/example",
+            "//third_party\\example",
+        ] {
+            let res = invalid_path.parse::<CanonicalPath>();
+            assert!(res.is_err(), "Expected Err for {invalid_path}");
+        }
+
+        for outside_path in [
+            "//",
+            "//third_party",
+            "//src/example",
+            "//third_party_x/example",
+        ] {
+            let res = outside_path.parse::<CanonicalPath>()?.to_abs(&paths);
+            assert!(res.is_err(), "Expected Err for {outside_path}");
+        }
+
+        for outside_dir in [
+            temp_dir.path().join("third_party/../src"),
+            Path::new("/etc").to_path_buf(),
+        ] {
+            let res = CanonicalPath::from_abs(&paths, &outside_dir);
+            assert!(res.is_err(), "Expected Err for {}", outside_dir.display());
+        }
+
+        Ok(())
+    }
+    #[test]
+    fn test_resolve_dependency() -> anyhow::Result<()> {
+        let temp_dir = create_monorepo()?;
+        let root = temp_dir.path();
+        for dep in ["example", "org/libbar", "other/libbar", "libfoo"] {
+            let dep_dir = root.join("third_party").join(dep);
+            fs::create_dir_all(dep_dir.join("repo/src"))?;
+            fs::write(dep_dir.join("dep_info.json"), "{}")?;
+        }
+        fs::create_dir_all(root.join("src/libfoo"))?;
+        let paths = MonorepoPaths::from_dir(root)?;
+
+        let resolve = |arg: Option<&str>, cwd: &str| -> anyhow::Result<String> {
+            let arg: Option<DependencyArg> = arg.map(str::parse).transpose()?;
+            let path = paths.resolve_dependency_from(arg.as_ref(), &root.join(cwd))?;
+            Ok(path.to_string())
+        };
+        let resolve_err = |arg: Option<&str>, cwd: &str| -> String {
+            match resolve(arg, cwd) {
+                Ok(path) => panic!("Expected Err for {arg:?} in {cwd}, but got {path}"),
+                Err(err) => format!("{err:#}"),
+            }
+        };
+
+        // Nearest dependency up from the current dir
+        assert_eq!(
+            resolve(None, "third_party/libfoo/repo/src")?,
+            "//third_party/libfoo"
+        );
+        assert_eq!(
+            resolve(None, "third_party/org/libbar")?,
+            "//third_party/org/libbar"
+        );
+        resolve_err(None, "third_party/org");
+        resolve_err(None, "src");
+
+        // Canonical and filesystem paths
+        assert_eq!(
+            resolve(Some("//third_party/libfoo/"), "src")?,
+            "//third_party/libfoo"
+        );
+        assert_eq!(
+            resolve(Some("../org/libbar/repo"), "third_party/other")?,
+            "//third_party/org/libbar"
+        );
+        assert_eq!(
+            resolve(Some("libbar"), "third_party/org")?,
+            "//third_party/org/libbar"
+        );
+        assert_eq!(
+            resolve(Some("."), "third_party/example/repo")?,
+            "//third_party/example"
+        );
+        resolve_err(Some("../../.."), "third_party/example");
+        resolve_err(Some("../org"), "third_party/example");
+
+        // Bare names
+        assert_eq!(resolve(Some("example"), "")?, "//third_party/example");
+        assert_eq!(resolve(Some("libfoo"), "src")?, "//third_party/libfoo");
+        let err = resolve_err(Some("libbar"), "");
+        assert!(err.contains("ambiguous"), "{err}");
+        assert!(
+            err.contains("//third_party/org/libbar, //third_party/other/libbar"),
+            "{err}"
+        );
+        let err = resolve_err(Some("exampel"), "");
+        assert!(err.contains("did you mean: //third_party/example"), "{err}");
+        let err = resolve_err(Some("//third_party/libfo"), "");
+        assert!(err.contains("did you mean: //third_party/libfoo"), "{err}");
+        assert!(!err.contains("example"), "{err}");
+
+        // New dependencies
+        let new_path = |arg: &str, cwd: &str| -> anyhow::Result<String> {
+            let path = paths.resolve_new_dependency_from(&arg.parse()?, &root.join(cwd))?;
+            Ok(path.to_string())
+        };
+        assert_eq!(new_path("new_dep", "third_party")?, "//third_party/new_dep");
+        assert_eq!(
+            new_path("third_party/org/new_dep", "")?,
+            "//third_party/org/new_dep"
+        );
+        assert!(new_path("new_dep", "").is_err());
+
+        // The monorepo reached through a symlink
+        let outside_dir = tempdir()?;
+        let link = outside_dir.path().join("monorepo");
+        std::os::unix::fs::symlink(root, &link)?;
+        let path = paths.resolve_dependency_from(None, &link.join("third_party/libfoo/repo"))?;
+        assert_eq!(path.to_string(), "//third_party/libfoo");
+        let path =
+            paths.resolve_new_dependency_from(&"new_dep".parse()?, &link.join("third_party"))?;
+        assert_eq!(path.to_string(), "//third_party/new_dep");
+        let linked_paths = MonorepoPaths::from_dir(&link)?;
+        let path = linked_paths.resolve_dependency_from(None, &root.join("third_party/libfoo"))?;
+        assert_eq!(path.to_string(), "//third_party/libfoo");
+
+        // Dirs outside of the monorepo don't shadow names
+        fs::create_dir(outside_dir.path().join("example"))?;
+        let path = paths.resolve_dependency_from(Some(&"example".parse()?), outside_dir.path())?;
+        assert_eq!(path.to_string(), "//third_party/example");
+
+        Ok(())
+    }
+    #[test]
+    fn test_find_repo_root_by_git_file() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let cwd = root.join("third_party/example");
+        fs::create_dir_all(&cwd)?;
+
+        let res = resolve_repo_root(None, None, &cwd);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        // Git worktrees and submodules have `.git` file instead of a dir.
+        fs::write(
+            root.join(".git"),
+            "gitdir: /src/monorepo/.git/worktrees/wt\n",
+        )?;
+        assert_eq!(resolve_repo_root(None, None, &cwd)?, root);
+
+        fs::write(cwd.join(".git"), "not a git file\n")?;
+        assert_eq!(resolve_repo_root(None, None, &cwd)?, root);
+
+        fs::create_dir_all(root.join("third_party/.git"))?;
+        assert_eq!(
+            resolve_repo_root(None, None, &cwd)?,
+            root.join("third_party")
+        );
+
+        Ok(())
+    }
+    #[test]
+    fn test_find_repo_root_by_marker_file() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let cwd = root.join("monorepo/third_party/example/repo");
+        fs::create_dir_all(&cwd)?;
+
+        fs::write(root.join("monorepo/WORKSPACE"), "")?;
+        assert_eq!(resolve_repo_root(None, None, &cwd)?, root.join("monorepo"));
+
+        // Vendored Bazel projects have their own WORKSPACE, VCS markers win
+        // over it.
+        let src_dir = cwd.join("absl");
+        fs::create_dir_all(&src_dir)?;
+        fs::write(cwd.join("WORKSPACE"), "")?;
+        assert_eq!(resolve_repo_root(None, None, &src_dir)?, cwd);
+        fs::create_dir_all(root.join("monorepo/.git"))?;
+        assert_eq!(
+            resolve_repo_root(None, None, &src_dir)?,
+            root.join("monorepo")
+        );
+
+        // dockyard.toml wins over markers closer to the current dir, including
+        // the ones inside of vendored code.
+        fs::create_dir_all(cwd.join(".git"))?;
+        fs::write(root.join("monorepo").join(CONFIG_FILE), "")?;
+        fs::write(cwd.join(CONFIG_FILE), "")?;
+        assert_eq!(resolve_repo_root(None, None, &cwd)?, root.join("monorepo"));
+
+        Ok(())
+    }
+    #[test]
+    fn test_find_repo_root_explicit() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let root = fs::canonicalize(temp_dir.path())?;
+        let cwd = root.join("checkout/third_party");
+        fs::create_dir_all(&cwd)?;
+        fs::create_dir_all(root.join("other"))?;
+        fs::create_dir_all(root.join("checkout/.git"))?;
+
+        // DOCKYARD_ROOT, relative to the current dir.
+        let env_root = OsString::from("../../other");
+        assert_eq!(
+            resolve_repo_root(None, Some(&env_root), &cwd)?,
+            root.join("other")
+        );
+        assert_eq!(
+            resolve_repo_root(None, Some(OsStr::new("")), &cwd)?,
+            root.join("checkout")
+        );
+
+        // --root takes precedence over DOCKYARD_ROOT.
+        assert_eq!(
+            resolve_repo_root(Some(&root.join("checkout")), Some(&env_root), &cwd)?,
+            root.join("checkout")
+        );
+
+        for missing_root in [root.join("missing"), root.join("checkout/.git/HEAD")] {
+            let res = resolve_repo_root(Some(&missing_root), None, &cwd);
+            assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        }
+        fs::write(root.join("checkout/.git/HEAD"), "")?;
+        let res = resolve_repo_root(
+            None,
+            Some(root.join("checkout/.git/HEAD").as_os_str()),
+            &cwd,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        Ok(())
+    }
+    #[test]
+    fn test_multiple_third_party_roots() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        for dir in ["third_party", "vendor/go", "external/js"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        fs::write(
+            root.join(CONFIG_FILE),
+            r#"[roots]
+third_party = "third_party"
+go = "vendor/go"
+js = "external/js"
+"#,
+        )?;
+        for dep in ["vendor/go/upstream", "external/js/org/upstream"] {
+            fs::create_dir_all(root.join(dep))?;
+            fs::write(root.join(dep).join("dep_info.json"), "{}")?;
+        }
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        assert_eq!(paths.roots.len(), 3);
+        let deps: Vec<_> = paths
+            .find_dependencies()?
+            .iter()
+            .map(|dir| Some(paths.root_of(dir)?.name.as_str()))
+            .collect();
+        assert_eq!(deps, vec![Some("js"), Some("go")]);
+
+        for canonical_path in ["//vendor/upstream", "//vendor/go", "//src/upstream"] {
+            let res = path_to_abs(&paths, canonical_path);
+            assert!(res.is_err(), "Expected Err for {canonical_path}");
+        }
+
+        for config in [
+            "third_party = \"vendor/go\"\n[roots]\ngo = \"vendor/go\"\n",
+            "[roots]\ngo = \"vendor\"\ngo_nested = \"vendor/go\"\n",
+            "[roots]\n\"go/mod\" = \"vendor/go\"\n",
+            "[roots]\ngo = \"../vendor\"\n",
+        ] {
+            fs::write(root.join(CONFIG_FILE), config)?;
+            let res = MonorepoPaths::from_dir(root);
+            assert!(res.is_err(), "Expected Err for {config:?}");
+        }
+
+        Ok(())
+    }
+}
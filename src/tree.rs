@@ -0,0 +1,151 @@
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Difference between two directory trees. Paths are relative to the tree
+/// roots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeDiff {
+    /// File exists only in the actual tree.
+    Added(PathBuf),
+    /// File exists only in the expected tree.
+    Removed(PathBuf),
+    /// File content, type or permissions differ.
+    Modified(PathBuf),
+}
+
+impl Display for TreeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TreeDiff::Added(path) => write!(f, "A {}", path.display()),
+            TreeDiff::Removed(path) => write!(f, "D {}", path.display()),
+            TreeDiff::Modified(path) => write!(f, "M {}", path.display()),
+        }
+    }
+}
+
+/// Compares two directory trees byte-for-byte. The `.git` dir in the root of
+/// either tree is ignored.
+pub fn diff_trees(expected: &Path, actual: &Path) -> io::Result<Vec<TreeDiff>> {
+    let expected_files = list_files(expected)?;
+    let actual_files = list_files(actual)?;
+
+    let mut diffs = Vec::new();
+    for file in &expected_files {
+        if actual_files.binary_search(file).is_err() {
+            diffs.push(TreeDiff::Removed(file.clone()));
+        } else if !same_file(&expected.join(file), &actual.join(file))? {
+            diffs.push(TreeDiff::Modified(file.clone()));
+        }
+    }
+    for file in &actual_files {
+        if expected_files.binary_search(file).is_err() {
+            diffs.push(TreeDiff::Added(file.clone()));
+        }
+    }
+    diffs.sort_by(|a, b| diff_path(a).cmp(diff_path(b)));
+
+    Ok(diffs)
+}
+
+fn diff_path(diff: &TreeDiff) -> &Path {
+    match diff {
+        TreeDiff::Added(path) | TreeDiff::Removed(path) | TreeDiff::Modified(path) => path,
+    }
+}
+
+/// Computes a deterministic SHA-256 hash of the tree under `root`. The hash
+/// covers relative paths, file types, executable bits and contents, but not
+/// timestamps or ownership. The `.git` dir in the root is ignored.
+pub fn hash_tree(root: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    for file in list_files(root)? {
+        let path = root.join(&file);
+        let meta = fs::symlink_metadata(&path)?;
+        let (mode, content) = if meta.file_type().is_symlink() {
+            let target = fs::read_link(&path)?;
+            ("120000", target.to_string_lossy().into_owned().into_bytes())
+        } else if is_executable(&meta) {
+            ("100755", fs::read(&path)?)
+        } else {
+            ("100644", fs::read(&path)?)
+        };
+
+        let components: Vec<_> = file
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect();
+        hasher.update(components.join("/").as_bytes());
+        hasher.update([0]);
+        hasher.update(mode.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_be_bytes());
+        hasher.update(&content);
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Computes SHA-256 hash of the file content.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Lists files and symlinks under `root` recursively, sorted by path.
+pub fn list_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            if path == Path::new(".git") {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let (a_meta, b_meta) = (fs::symlink_metadata(a)?, fs::symlink_metadata(b)?);
+    if a_meta.file_type().is_symlink() || b_meta.file_type().is_symlink() {
+        return Ok(a_meta.file_type().is_symlink()
+            && b_meta.file_type().is_symlink()
+            && fs::read_link(a)? == fs::read_link(b)?);
+    }
+    if is_executable(&a_meta) != is_executable(&b_meta) {
+        return Ok(false);
+    }
+
+    Ok(a_meta.len() == b_meta.len() && fs::read(a)? == fs::read(b)?)
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
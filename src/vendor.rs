@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::fmt::Display;
 use std::fs;
 use std::fs::File;
@@ -6,16 +7,35 @@ use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::Stdio;
 
 use crate::ExtractPatchCommandArgs;
+use crate::ListCommandArgs;
+use crate::OutdatedCommandArgs;
+use crate::OutputFormat;
+use crate::PatchCommand;
+use crate::PatchCommandArgs;
 use crate::UpdateCommandArgs;
 use crate::VendorCommandArgs;
+use crate::VerifyCommandArgs;
+use crate::WorkspaceCommand;
+use crate::WorkspaceCommandArgs;
+use crate::WorkspaceOpenArgs;
+use crate::WorkspaceSyncArgs;
 use crate::paths;
 use anyhow::Context;
 use anyhow::bail;
 use anyhow::{Result, anyhow};
+use dockyard::config::TemplatesConfig;
+use dockyard::config::render_template;
+use dockyard::paths::CanonicalPath;
+use dockyard::paths::DependencyArg;
 use dockyard::paths::MonorepoPaths;
-use dockyard::paths::path_to_abs;
+use dockyard::tree::TreeDiff;
+use dockyard::tree::diff_trees;
+use dockyard::tree::hash_file;
+use dockyard::tree::hash_tree;
+use dockyard::tree::list_files;
 use dockyard::utils::run_command;
 use serde::Deserialize;
 use serde::Serialize;
@@ -23,16 +43,84 @@ use serde::Serialize;
 #[derive(Serialize, Deserialize, Clone)]
 struct DependencyMetadata {
     url: String,
-    version: String,
+    #[serde(flatten)]
+    revision: UpstreamRevision,
+    /// Glob pattern of release tags. If set, `update` and `outdated` follow
+    /// the newest matching tag instead of a branch.
+    #[serde(default)]
+    tag_pattern: Option<String>,
+    /// Hash of the vendored tree with applied patches, see `hash_tree`.
+    #[serde(default)]
+    patched_tree_hash: Option<String>,
+    /// Set if the dependency is vendored from a release archive, `url` points
+    /// to the archive then.
+    #[serde(default)]
+    archive: Option<ArchiveSource>,
+    /// Subdir of the upstream tree vendored into the repo dir. The whole tree
+    /// is vendored if it's not set.
+    #[serde(default)]
+    subdir: Option<String>,
+    #[serde(flatten)]
+    filter: FileFilter,
     update_state: Option<UpdateState>,
 }
 
+/// Glob patterns selecting vendored files. Patterns are matched against paths
+/// relative to the repo dir, `*` doesn't cross `/` and a pattern matching a
+/// dir applies to all files under it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+struct FileFilter {
+    /// Only files matching one of the patterns are vendored. All files are
+    /// vendored if it's empty.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Files matching one of the patterns are removed after fetching upstream.
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct ArchiveSource {
+    /// SHA-256 checksum of the archive.
+    sha256: String,
+    /// Number of leading path components stripped from archive entries.
+    strip_components: u32,
+}
+
+/// Upstream revision the vendored code was imported from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct UpstreamRevision {
+    /// Tag, branch or commit requested by the user. `None` if the default
+    /// branch was imported.
+    requested_ref: Option<String>,
+    /// Commit hash of the imported tree. For archive sources it's the
+    /// archive checksum in the `sha256:<hex>` format.
+    resolved_commit: String,
+    /// Upstream branch followed by `update` when no version is provided.
+    tracked_branch: Option<String>,
+    /// Hash of the pristine upstream tree, see `hash_tree`.
+    #[serde(default)]
+    upstream_tree_hash: Option<String>,
+}
+
+impl Display for UpstreamRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.requested_ref {
+            Some(ref requested_ref) if *requested_ref != self.resolved_commit => {
+                write!(f, "{} ({})", requested_ref, self.resolved_commit)
+            }
+            _ => write!(f, "{}", self.resolved_commit),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 enum PatchState {
     Pending,
     Applied,
     Conflict,
     Resolved,
+    Skipped,
 }
 
 impl Display for PatchState {
@@ -42,6 +130,7 @@ impl Display for PatchState {
             PatchState::Conflict => write!(f, "Conflict"),
             PatchState::Applied => write!(f, "Applied"),
             PatchState::Resolved => write!(f, "Resolved"),
+            PatchState::Skipped => write!(f, "Skipped"),
         }
     }
 }
@@ -55,98 +144,730 @@ struct PatchApplyState {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct UpdateState {
     prev_commit_hash: String,
-    new_version: String,
+    new_revision: UpstreamRevision,
     patches: Vec<PatchApplyState>,
+    /// Branch the update is committed to, see `update --branch`.
+    #[serde(default)]
+    branch: Option<String>,
+    /// Branch checked out before the update. `None` if HEAD was detached.
+    #[serde(default)]
+    original_branch: Option<String>,
+    /// Squash the update commits once all patches are applied, see
+    /// `update --squash`.
+    #[serde(default)]
+    squash: bool,
+    /// Upstream commits between the previous and the new revision, `<short
+    /// hash> <subject>`. Recorded only for squashed updates.
+    #[serde(default)]
+    upstream_shortlog: Vec<String>,
+    /// Stage the update steps instead of committing them, see
+    /// `update --no-commit`. Progress is tracked only here then.
+    #[serde(default)]
+    no_commit: bool,
+    /// Monorepo tree before the update. Abort restores the dependency from it
+    /// in no-commit mode.
+    #[serde(default)]
+    snapshot_tree: Option<String>,
+    /// Monorepo tree with the last completed update step staged. Replaces
+    /// HEAD as the base of the next step in no-commit mode.
+    #[serde(default)]
+    checkpoint_tree: Option<String>,
+    /// Patch edited with `dockyard patch edit`. The upstream revision stays
+    /// the same then, only the subsequent patches are rebased.
+    #[serde(default)]
+    edited_patch: Option<String>,
 }
 
-const DEP_INFO: &str = "dep_info.json";
+impl UpdateState {
+    /// Revision the current update step is based on.
+    fn base_rev(&self) -> &str {
+        self.checkpoint_tree.as_deref().unwrap_or("HEAD")
+    }
+}
 
 pub fn vendor(args: VendorCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
-    let target_dir = path_to_abs(paths, &args.path)?;
+    let target_dir = paths.resolve_new_dependency(&args.path)?.to_abs(paths)?;
 
     if target_dir.exists() {
         return Err(anyhow!("Target must be empty: {}", target_dir.display()));
     }
+    let subdir = args.subdir.as_deref().map(normalize_subdir).transpose()?;
+    let filter = FileFilter {
+        include: args.include,
+        exclude: args.exclude,
+    };
+    compile_filter(&filter)?;
+    let url = match args.archive {
+        Some(ref archive) => archive_location(archive)?,
+        None => args.git.clone().unwrap(),
+    };
+    paths.config.upstream.check_url(&url)?;
     fs::create_dir_all(&target_dir)?;
 
-    let clone_dir = target_dir.join("repo");
-    let mut clone_cmd = Command::new("git");
-    clone_cmd.args(["clone", &args.git, clone_dir.to_str().unwrap()]);
+    let clone_dir = paths.repo_dir(&target_dir);
+    let fetched = if args.archive.is_some() {
+        let archive = ArchiveSource {
+            sha256: args.sha256.clone().unwrap(),
+            strip_components: args.strip_components.unwrap_or(0),
+        };
+        fetch_archive(&url, &archive, subdir.as_deref(), &clone_dir)
+            .map(|revision| (revision, Some(archive)))
+    } else {
+        fetch_upstream(&url, args.version.as_deref(), subdir.as_deref(), &clone_dir)
+            .map(|revision| (revision, None))
+    };
+    let fetched = fetched.and_then(|(mut revision, archive)| {
+        filter_upstream_tree(&clone_dir, &filter, &mut revision)?;
+        Ok((revision, archive))
+    });
+    let (revision, archive) = match fetched {
+        Ok(fetched) => fetched,
+        Err(err) => {
+            fs::remove_dir_all(&target_dir)?;
+            return Err(err);
+        }
+    };
+
+    let meta = DependencyMetadata {
+        url,
+        patched_tree_hash: revision.upstream_tree_hash.clone(),
+        revision,
+        tag_pattern: args.tag_pattern,
+        archive,
+        subdir,
+        filter,
+        update_state: None,
+    };
+    update_metadata(paths, &target_dir, &meta)?;
+
+    Ok(())
+}
+
+/// Clones `url` and checks out `version` (tag, branch or commit) into
+/// `dest`. The default branch is used if `version` isn't provided. Only
+/// `subdir` of the upstream tree is checked out if it's provided. `dest`
+/// contains only the upstream tree without the `.git` dir.
+fn fetch_upstream(
+    url: &str,
+    version: Option<&str>,
+    subdir: Option<&str>,
+    dest: &Path,
+) -> Result<UpstreamRevision> {
+    // Keep the scratch dir on the same filesystem as `dest` to move the
+    // checked out tree instead of copying it.
+    let scratch_dir = tempfile::Builder::new()
+        .prefix(".clone")
+        .tempdir_in(dest.parent().unwrap())?;
+    let clone_dir = scratch_dir.path().join("repo");
 
+    let mut clone_cmd = Command::new("git");
+    clone_cmd.args(["clone", "--no-checkout"]);
+    if subdir.is_some() {
+        // Fetch blobs only for the checked out subdir if the server allows
+        clone_cmd.arg("--filter=blob:none");
+    }
+    clone_cmd.args([url, clone_dir.to_str().unwrap()]);
     run_command(clone_cmd, "clone", None).context("Failed to clone repo")?;
 
-    // Checkout version if it's provided (tag/branch/commit)
-    let version_str = if let Some(version) = args.version {
-        let mut checkout_version_cmd = Command::new("git");
-        checkout_version_cmd
-            .current_dir(&clone_dir)
-            .args(["checkout", &version]);
-        run_command(checkout_version_cmd, "clone", None)
-            .context("Failed to checkout specific version")?;
-        version
+    if let Some(subdir) = subdir {
+        let mut sparse_checkout_cmd = Command::new("git");
+        sparse_checkout_cmd.current_dir(&clone_dir).args([
+            "sparse-checkout",
+            "set",
+            "--no-cone",
+            &format!("/{subdir}/"),
+        ]);
+        run_command(sparse_checkout_cmd, "sparse-checkout", None)
+            .context("Failed to set up sparse checkout")?;
+    }
+
+    let mut revision = if let Some(version) = version {
+        let (commit, is_branch) = resolve_commit(&clone_dir, version)
+            .with_context(|| format!("Version {version} not found in {url}"))?;
+
+        UpstreamRevision {
+            requested_ref: Some(version.to_string()),
+            resolved_commit: commit,
+            tracked_branch: is_branch.then(|| version.to_string()),
+            upstream_tree_hash: None,
+        }
     } else {
-        let version_cmd = Command::new("git")
+        let branch_cmd = Command::new("git")
             .current_dir(&clone_dir)
-            .args(["rev-parse", "HEAD"])
+            .args(["rev-parse", "--abbrev-ref", "HEAD"])
             .output()?;
-        if !version_cmd.status.success() {
-            return Err(anyhow!("git rev-parse failed"));
+        if !branch_cmd.status.success() {
+            bail!("git rev-parse failed");
+        }
+
+        UpstreamRevision {
+            requested_ref: None,
+            resolved_commit: get_current_commit(&clone_dir)?,
+            tracked_branch: Some(String::from_utf8(branch_cmd.stdout)?.trim().to_string()),
+            upstream_tree_hash: None,
         }
-        String::from_utf8(version_cmd.stdout)?.trim().to_string()
     };
-    fs::remove_dir_all(clone_dir.join(".git"))?;
 
-    let meta = DependencyMetadata {
-        url: args.git.to_string(),
-        version: version_str.to_string(),
-        update_state: None,
+    let commit = &revision.resolved_commit;
+    let mut checkout_version_cmd = Command::new("git");
+    checkout_version_cmd
+        .current_dir(&clone_dir)
+        .args(["checkout", "--quiet", "--detach", commit]);
+    run_command(checkout_version_cmd, "checkout", None)
+        .with_context(|| format!("Failed to checkout {commit}"))?;
+
+    let checked_out = get_current_commit(&clone_dir)?;
+    if checked_out != *commit {
+        bail!(
+            "Checked out commit {} doesn't match the resolved commit {}",
+            checked_out,
+            commit,
+        );
+    }
+
+    if let Some(subdir) = subdir {
+        let subdir_path = clone_dir.join(subdir);
+        if !subdir_path.is_dir() {
+            bail!("Subdir {} not found in {} at {}", subdir, url, commit);
+        }
+        fs::rename(subdir_path, dest)?;
+    } else {
+        fs::remove_dir_all(clone_dir.join(".git"))?;
+        fs::rename(&clone_dir, dest)?;
+    }
+    revision.upstream_tree_hash = Some(hash_tree(dest)?);
+
+    Ok(revision)
+}
+
+/// Downloads the release archive from `url` (http(s), file:// or local path),
+/// checks its checksum and extracts it into `dest`. Only `subdir` of the
+/// archive tree (after stripping path components) is extracted if it's
+/// provided.
+fn fetch_archive(
+    url: &str,
+    archive: &ArchiveSource,
+    subdir: Option<&str>,
+    dest: &Path,
+) -> Result<UpstreamRevision> {
+    // Keep the scratch dir on the same filesystem as `dest` to move the
+    // extracted files instead of copying them.
+    let scratch_dir = tempfile::Builder::new()
+        .prefix(".archive")
+        .tempdir_in(dest.parent().unwrap())?;
+
+    let file_name = url
+        .split(['?', '#'])
+        .next()
+        .unwrap()
+        .rsplit('/')
+        .next()
+        .unwrap();
+    let archive_path = scratch_dir.path().join(file_name);
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let mut download_cmd = Command::new("curl");
+        download_cmd
+            .args(["-fsSL", "-o"])
+            .arg(&archive_path)
+            .arg(url);
+        run_command(download_cmd, "curl", None).context("Failed to download archive")?;
+    } else {
+        let path = url.strip_prefix("file://").unwrap_or(url);
+        fs::copy(path, &archive_path).with_context(|| format!("Failed to copy archive {path}"))?;
+    }
+
+    let sha256 = hash_file(&archive_path)?;
+    if !sha256.eq_ignore_ascii_case(&archive.sha256) {
+        bail!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            url,
+            archive.sha256,
+            sha256
+        );
+    }
+
+    let extracted_dir = scratch_dir.path().join("extracted");
+    fs::create_dir_all(&extracted_dir)?;
+    let mut extract_cmd;
+    if file_name.ends_with(".zip") {
+        extract_cmd = Command::new("unzip");
+        extract_cmd
+            .arg("-q")
+            .arg(&archive_path)
+            .arg("-d")
+            .arg(&extracted_dir);
+    } else if [
+        ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.bz2", ".tbz2",
+    ]
+    .iter()
+    .any(|ext| file_name.ends_with(ext))
+    {
+        // tar detects compression by itself
+        extract_cmd = Command::new("tar");
+        extract_cmd
+            .arg("-xf")
+            .arg(&archive_path)
+            .arg("-C")
+            .arg(&extracted_dir);
+    } else {
+        bail!("Unsupported archive format: {}", file_name);
+    }
+    run_command(extract_cmd, "extract archive", None).context("Failed to extract archive")?;
+
+    fs::create_dir_all(dest)?;
+    let mut files_count = 0;
+    for file in list_files(&extracted_dir)? {
+        let mut stripped: PathBuf = file
+            .components()
+            .skip(archive.strip_components as usize)
+            .collect();
+        if let Some(subdir) = subdir {
+            match stripped.strip_prefix(subdir) {
+                Ok(path) => stripped = path.to_path_buf(),
+                Err(_) => continue,
+            }
+        }
+        if stripped.as_os_str().is_empty() {
+            continue;
+        }
+        let file_dest = dest.join(stripped);
+        fs::create_dir_all(file_dest.parent().unwrap())?;
+        fs::rename(extracted_dir.join(&file), file_dest)?;
+        files_count += 1;
+    }
+    if files_count == 0 {
+        bail!(
+            "No files left in {} after stripping {} path components{}",
+            url,
+            archive.strip_components,
+            subdir
+                .map(|s| format!(" and selecting {s}"))
+                .unwrap_or_default()
+        );
+    }
+
+    Ok(UpstreamRevision {
+        requested_ref: None,
+        resolved_commit: format!("sha256:{sha256}"),
+        tracked_branch: None,
+        upstream_tree_hash: Some(hash_tree(dest)?),
+    })
+}
+
+/// Compiles include and exclude patterns of `filter`.
+fn compile_filter(filter: &FileFilter) -> Result<(Vec<glob::Pattern>, Vec<glob::Pattern>)> {
+    let compile = |patterns: &[String]| {
+        patterns
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("Invalid file pattern {pattern}"))
+            })
+            .collect::<Result<Vec<_>>>()
+    };
+
+    Ok((compile(&filter.include)?, compile(&filter.exclude)?))
+}
+
+/// Removes files not selected by `filter` from the fetched upstream tree in
+/// `dir` and updates the upstream tree hash of `revision` accordingly.
+fn filter_upstream_tree(
+    dir: &Path,
+    filter: &FileFilter,
+    revision: &mut UpstreamRevision,
+) -> Result<()> {
+    if filter.include.is_empty() && filter.exclude.is_empty() {
+        return Ok(());
+    }
+
+    let (include, exclude) = compile_filter(filter)?;
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
     };
-    update_metadata(&target_dir, &meta)?;
+    // Pattern matching a dir selects all files under it
+    let matches = |patterns: &[glob::Pattern], file: &Path| {
+        file.ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| {
+                patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path_with(path, options))
+            })
+    };
+
+    for file in list_files(dir)? {
+        let included = include.is_empty() || matches(&include, &file);
+        if included && !matches(&exclude, &file) {
+            continue;
+        }
+
+        fs::remove_file(dir.join(&file))?;
+        // Drop dirs left empty, stops at the first non-empty one
+        for parent in file.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() || fs::remove_dir(dir.join(parent)).is_err() {
+                break;
+            }
+        }
+    }
+    revision.upstream_tree_hash = Some(hash_tree(dir)?);
 
     Ok(())
 }
 
-fn update_metadata(target_dir: &PathBuf, metadata: &DependencyMetadata) -> Result<()> {
+/// Normalizes upstream subdir to the `a/b` form.
+fn normalize_subdir(subdir: &str) -> Result<String> {
+    let components: Vec<_> = subdir.split('/').filter(|c| !c.is_empty()).collect();
+    if components.is_empty() || components.iter().any(|c| *c == "." || *c == "..") {
+        bail!("Invalid subdir: {}", subdir);
+    }
+
+    Ok(components.join("/"))
+}
+
+/// Turns local archive path into absolute one, so it stays valid when
+/// recorded in metadata. Urls are kept as is.
+fn archive_location(archive: &str) -> Result<String> {
+    if ["http://", "https://", "file://"]
+        .iter()
+        .any(|scheme| archive.starts_with(scheme))
+    {
+        return Ok(archive.to_string());
+    }
+
+    let path =
+        fs::canonicalize(archive).with_context(|| format!("Archive not found: {archive}"))?;
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Returns the new archive url and source for updating an archive dependency.
+fn update_archive_source(
+    args: &UpdateCommandArgs,
+    metadata: &DependencyMetadata,
+) -> Result<(String, ArchiveSource)> {
+    match (&metadata.archive, &args.archive) {
+        (Some(prev), Some(archive)) => Ok((
+            archive_location(archive)?,
+            ArchiveSource {
+                sha256: args.sha256.clone().unwrap(),
+                strip_components: args.strip_components.unwrap_or(prev.strip_components),
+            },
+        )),
+        (Some(_), None) => {
+            bail!(
+                "Dependency is vendored from archive, provide the new one with --archive and --sha256"
+            )
+        }
+        (None, _) => bail!("Dependency is vendored from git, --archive can't be used"),
+    }
+}
+
+/// Resolves tag, remote branch name or commit to the commit hash in the
+/// cloned repo. Returns the hash and whether `version` is a branch.
+fn resolve_commit(repo_dir: &Path, version: &str) -> Result<(String, bool)> {
+    for (rev, is_branch) in [
+        (format!("refs/tags/{version}^{{commit}}"), false),
+        (format!("refs/remotes/origin/{version}^{{commit}}"), true),
+        (format!("{version}^{{commit}}"), false),
+    ] {
+        let rev_parse_cmd = Command::new("git")
+            .current_dir(repo_dir)
+            .args(["rev-parse", "--verify", "--quiet", &rev])
+            .output()?;
+        if rev_parse_cmd.status.success() {
+            let commit = String::from_utf8(rev_parse_cmd.stdout)?.trim().to_string();
+            return Ok((commit, is_branch));
+        }
+    }
+
+    bail!("Unknown revision {version}");
+}
+
+fn update_metadata(
+    paths: &MonorepoPaths,
+    target_dir: &Path,
+    metadata: &DependencyMetadata,
+) -> Result<()> {
     let json = serde_json::to_string_pretty(&metadata)?;
-    fs::write(target_dir.join(DEP_INFO), json)?;
+    fs::write(paths.metadata_file(target_dir), json)?;
 
     Ok(())
 }
 
-fn load_metadata(target_dir: &PathBuf) -> Result<DependencyMetadata> {
-    let file = File::open(target_dir.join(DEP_INFO))?;
+fn load_metadata(paths: &MonorepoPaths, target_dir: &Path) -> Result<DependencyMetadata> {
+    let metadata_file = paths.metadata_file(target_dir);
+    let file = File::open(&metadata_file)
+        .with_context(|| format!("Failed to open {}", metadata_file.display()))?;
     let reader = BufReader::new(file);
 
-    let metadata: DependencyMetadata = serde_json::from_reader(reader)?;
+    let mut value: serde_json::Value = serde_json::from_reader(reader)?;
+    let migrated = migrate_metadata(&mut value)
+        .with_context(|| format!("Failed to migrate {}", metadata_file.display()))?;
+    let metadata: DependencyMetadata = serde_json::from_value(value)?;
+
+    if migrated {
+        update_metadata(paths, target_dir, &metadata)?;
+    }
 
     Ok(metadata)
 }
 
-fn get_update_version(args: &UpdateCommandArgs, metadata: &DependencyMetadata) -> Result<String> {
-    if let Some(ref version) = args.version {
-        Ok(version.clone())
-    } else {
-        let version_cmd = Command::new("git")
-            .args(["ls-remote", &metadata.url, "HEAD"])
-            .output()?;
-        if !version_cmd.status.success() {
-            bail!(
-                "git ls-remote failed, stdout: {}, stderr: {}",
-                String::from_utf8_lossy(&version_cmd.stdout),
-                String::from_utf8_lossy(&version_cmd.stderr),
-            );
+/// Upgrades metadata written before the single `version` field was split into
+/// the requested ref and the resolved commit. Returns `true` if the metadata
+/// was changed.
+///
+/// Legacy tag or branch versions are resolved to commits in the upstream, so
+/// the migration fails if the upstream isn't reachable.
+fn migrate_metadata(value: &mut serde_json::Value) -> Result<bool> {
+    let Some(metadata) = value.as_object_mut() else {
+        bail!("Metadata must be a JSON object");
+    };
+    let Some(version) = metadata.remove("version") else {
+        return Ok(false);
+    };
+    let Some(version) = version.as_str() else {
+        bail!("Legacy version must be a string, got {}", version);
+    };
+    let Some(url) = metadata.get("url").and_then(|u| u.as_str()) else {
+        bail!("Legacy metadata must have the upstream url");
+    };
+    let url = url.to_string();
+
+    for (key, value) in serde_json::to_value(legacy_revision(&url, version)?)?
+        .as_object()
+        .unwrap()
+    {
+        metadata.insert(key.clone(), value.clone());
+    }
+
+    if let Some(update_state) = metadata
+        .get_mut("update_state")
+        .and_then(|s| s.as_object_mut())
+        && let Some(new_version) = update_state.remove("new_version")
+    {
+        let Some(new_version) = new_version.as_str() else {
+            bail!("Legacy new version must be a string, got {}", new_version);
+        };
+        update_state.insert(
+            "new_revision".to_string(),
+            serde_json::to_value(legacy_revision(&url, new_version)?)?,
+        );
+    }
+
+    Ok(true)
+}
+
+/// Converts the legacy version to a revision. Commit hashes, full or
+/// abbreviated, are kept as is, tags and branches are resolved to commits in
+/// the upstream and branches keep being tracked.
+fn legacy_revision(url: &str, version: &str) -> Result<UpstreamRevision> {
+    let is_commit =
+        (4..=40).contains(&version.len()) && version.chars().all(|c| c.is_ascii_hexdigit());
+    if is_commit {
+        return Ok(UpstreamRevision {
+            requested_ref: None,
+            resolved_commit: version.to_string(),
+            tracked_branch: None,
+            upstream_tree_hash: None,
+        });
+    }
+
+    let (ref_name, resolved_commit) = ls_remote_ref(url, version)
+        .and_then(|found| found.ok_or_else(|| anyhow!("Unknown revision {version}")))
+        .with_context(|| {
+            format!(
+                "Failed to resolve legacy version {version} of {url} to a commit, \
+                retry with the upstream reachable"
+            )
+        })?;
+
+    Ok(UpstreamRevision {
+        requested_ref: Some(version.to_string()),
+        resolved_commit,
+        tracked_branch: ref_name
+            .strip_prefix("refs/heads/")
+            .map(|branch| branch.to_string()),
+        upstream_tree_hash: None,
+    })
+}
+
+/// Upstream version to update to.
+struct UpdateVersion {
+    /// Tag, branch or commit to checkout. `None` means the upstream HEAD.
+    requested_ref: Option<String>,
+    /// Commit hash of the version if the upstream has such ref.
+    commit: Option<String>,
+}
+
+/// Determines the upstream version to update to. An explicitly requested
+/// version wins, then the newest tag matching the tag pattern, then the
+/// tracked branch and finally the upstream HEAD.
+fn get_update_version(
+    version: Option<&str>,
+    metadata: &DependencyMetadata,
+) -> Result<UpdateVersion> {
+    if version.is_none()
+        && let Some(ref tag_pattern) = metadata.tag_pattern
+    {
+        let Some((tag, commit)) = latest_tag(&metadata.url, tag_pattern)? else {
+            bail!("No tags matching {} in {}", tag_pattern, metadata.url);
+        };
+        return Ok(UpdateVersion {
+            requested_ref: Some(tag),
+            commit: Some(commit),
+        });
+    }
+
+    let requested_ref = version
+        .map(|v| v.to_string())
+        .or_else(|| metadata.revision.tracked_branch.clone());
+    let commit = ls_remote(&metadata.url, requested_ref.as_deref().unwrap_or("HEAD"))?;
+
+    Ok(UpdateVersion {
+        requested_ref,
+        commit,
+    })
+}
+
+/// Looks up commit hash of `reference` (HEAD, tag or branch) in the remote
+/// repository. Returns `None` if the remote has no such ref, e.g. for commit
+/// hashes.
+fn ls_remote(url: &str, reference: &str) -> Result<Option<String>> {
+    Ok(ls_remote_ref(url, reference)?.map(|(_, hash)| hash))
+}
+
+/// Finds the `reference` in the remote repository. Returns the full name of
+/// the matching ref and its commit hash.
+fn ls_remote_ref(url: &str, reference: &str) -> Result<Option<(String, String)>> {
+    // Peeled annotated tags are listed only if asked for explicitly
+    let peeled = format!("{reference}^{{}}");
+    let refs = ls_remote_refs(url, &[], &[reference, &peeled])?;
+
+    let candidates = [
+        reference.to_string(),
+        format!("refs/tags/{reference}^{{}}"),
+        format!("refs/tags/{reference}"),
+        format!("refs/heads/{reference}"),
+    ];
+    for candidate in candidates {
+        if let Some(found) = refs.iter().find(|(name, _)| *name == candidate) {
+            return Ok(Some(found.clone()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Finds the newest tag matching glob `pattern` in the remote repository.
+/// Returns the tag name and its commit hash.
+fn latest_tag(url: &str, pattern: &str) -> Result<Option<(String, String)>> {
+    let pattern =
+        glob::Pattern::new(pattern).with_context(|| format!("Invalid tag pattern {pattern}"))?;
+
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for (name, hash) in ls_remote_refs(url, &["--tags"], &[])? {
+        let Some(tag) = name.strip_prefix("refs/tags/") else {
+            continue;
+        };
+        // Annotated tags are listed twice, the peeled one with ^{} suffix
+        // points to the commit.
+        if let Some(tag) = tag.strip_suffix("^{}") {
+            tags.retain(|(t, _)| t != tag);
+            tags.push((tag.to_string(), hash));
+        } else if !tags.iter().any(|(t, _)| t == tag) {
+            tags.push((tag.to_string(), hash));
+        }
+    }
+
+    Ok(tags
+        .into_iter()
+        .filter(|(tag, _)| pattern.matches(tag))
+        .max_by(|a, b| compare_versions(&a.0, &b.0)))
+}
+
+/// Runs `git ls-remote` and returns the list of (ref name, commit hash).
+fn ls_remote_refs(url: &str, options: &[&str], patterns: &[&str]) -> Result<Vec<(String, String)>> {
+    let ls_remote_cmd = Command::new("git")
+        .arg("ls-remote")
+        .args(options)
+        .arg(url)
+        .args(patterns)
+        .output()?;
+    if !ls_remote_cmd.status.success() {
+        bail!(
+            "git ls-remote failed, stdout: {}, stderr: {}",
+            String::from_utf8_lossy(&ls_remote_cmd.stdout),
+            String::from_utf8_lossy(&ls_remote_cmd.stderr),
+        );
+    }
+    let output = String::from_utf8(ls_remote_cmd.stdout)?;
+
+    // git ls-remote shows
+    // commit_hash ref_name
+    let mut refs = Vec::new();
+    for line in output.lines() {
+        let mut iter = line.split_whitespace();
+        match (iter.next(), iter.next()) {
+            (Some(hash), Some(name)) => refs.push((name.to_string(), hash.to_string())),
+            _ => bail!("Unexpected git ls-remote output: {}", output),
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Compares version strings so that numeric parts are ordered by value, e.g.
+/// v1.10 is newer than v1.9. Pre-release suffixes are older than the bare
+/// version, e.g. v1.2-rc1 is older than v1.2.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn chunks(s: &str) -> Vec<&str> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for (idx, c) in s.char_indices().skip(1) {
+            let prev = s[..idx].chars().next_back().unwrap();
+            if prev.is_ascii_digit() != c.is_ascii_digit() {
+                chunks.push(&s[start..idx]);
+                start = idx;
+            }
         }
-        let output = String::from_utf8(version_cmd.stdout)?.trim().to_string();
+        if start < s.len() {
+            chunks.push(&s[start..]);
+        }
+        chunks
+    }
+    fn is_numeric(chunk: &str) -> bool {
+        chunk.starts_with(|c: char| c.is_ascii_digit())
+    }
+    // Further numeric parts like .1 make the version newer, anything else
+    // like -rc1 or beta2 is a pre-release
+    fn is_release_suffix(rest: &[&str]) -> bool {
+        match rest {
+            [".", number, ..] => is_numeric(number),
+            [chunk, ..] => is_numeric(chunk),
+            [] => false,
+        }
+    }
 
-        // git ls-remote shows
-        // commit_hash HEAD
-        let mut iter = output.split_whitespace();
-        if let Some(version) = iter.next() {
-            Ok(version.to_string())
+    let (a_chunks, b_chunks) = (chunks(a), chunks(b));
+    for (a, b) in a_chunks.iter().zip(b_chunks.iter()) {
+        let ord = if is_numeric(a) && is_numeric(b) {
+            let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
         } else {
-            bail!("Unexpected git ls-remote output: {}", output);
+            a.cmp(b)
+        };
+        if ord != Ordering::Equal {
+            return ord;
         }
     }
+
+    match a_chunks.len().cmp(&b_chunks.len()) {
+        Ordering::Equal => Ordering::Equal,
+        Ordering::Greater if is_release_suffix(&a_chunks[b_chunks.len()..]) => Ordering::Greater,
+        Ordering::Greater => Ordering::Less,
+        Ordering::Less if is_release_suffix(&b_chunks[a_chunks.len()..]) => Ordering::Less,
+        Ordering::Less => Ordering::Greater,
+    }
 }
 
 pub fn get_current_commit(current_dir: &Path) -> Result<String> {
@@ -161,15 +882,17 @@ pub fn get_current_commit(current_dir: &Path) -> Result<String> {
 }
 
 pub fn update(args: UpdateCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
-    let canonical_path = &args.path.as_ref().unwrap();
+    let dep_path = paths.resolve_dependency(args.path.as_ref())?;
+    let canonical_path = dep_path.as_str();
 
-    let target_dir = path_to_abs(paths, &canonical_path)?;
+    let target_dir = dep_path.to_abs(paths)?;
 
     if !target_dir.exists() {
         bail!("Target not found: {}", target_dir.display());
     }
 
-    let mut metadata: DependencyMetadata = load_metadata(&target_dir)?;
+    let mut metadata: DependencyMetadata = load_metadata(paths, &target_dir)?;
+    let config = &paths.config;
 
     if args.status {
         if let Some(update_state) = metadata.update_state {
@@ -184,12 +907,39 @@ pub fn update(args: UpdateCommandArgs, paths: &paths::MonorepoPaths) -> Result<(
         return Ok(());
     }
 
+    if let Some(ref state) = metadata.update_state
+        && (args.abort || args.cont || args.skip)
+    {
+        ensure_on_update_branch(state, &paths.root)?;
+    }
+
     if args.abort {
-        if let Some(ref state) = metadata.update_state {
-            revert_to_commit(&state.prev_commit_hash, &paths.root)?;
+        if let Some(ref state) = metadata.update_state
+            && let Some(ref snapshot_tree) = state.snapshot_tree
+        {
+            restore_dir(&target_dir, snapshot_tree, &paths.root)?;
+            println!(
+                "Aborted update, restored {} from the snapshot",
+                canonical_path
+            );
+            return Ok(());
+        } else if let Some(ref state) = metadata.update_state {
+            abort_update(&target_dir, &state.prev_commit_hash, &paths.root)?;
+            if let Some(ref branch) = state.branch {
+                match state.original_branch {
+                    Some(ref original_branch) => {
+                        git_output(&paths.root, &["switch", "--quiet", original_branch])?
+                    }
+                    None => git_output(
+                        &paths.root,
+                        &["switch", "--quiet", "--detach", &state.prev_commit_hash],
+                    )?,
+                };
+                git_output(&paths.root, &["branch", "--quiet", "-D", branch])?;
+            }
             println!(
-                "Aborted update, reverted to commit {}",
-                state.prev_commit_hash
+                "Aborted update, restored {} to commit {}",
+                canonical_path, state.prev_commit_hash
             );
             return Ok(());
         } else {
@@ -198,41 +948,149 @@ pub fn update(args: UpdateCommandArgs, paths: &paths::MonorepoPaths) -> Result<(
     }
 
     if args.cont {
+        let Some(ref state) = metadata.update_state else {
+            bail!("No active update state");
+        };
+        if let Some(ref edited_patch) = state.edited_patch
+            && state
+                .patches
+                .iter()
+                .any(|p| &p.name == edited_patch && p.state == PatchState::Pending)
+        {
+            bail!(
+                "Patch {edited_patch} is being edited, finish it with `dockyard patch edit --finish`"
+            );
+        }
+        apply_patches(
+            &target_dir,
+            canonical_path,
+            paths,
+            &config.templates,
+            &mut metadata,
+        )?;
+    } else if args.skip {
         if metadata.update_state.is_none() {
             bail!("No active update state");
         }
-        apply_patches(&target_dir, &canonical_path, paths, &mut metadata)?;
+        skip_conflicted_patch(
+            &target_dir,
+            canonical_path,
+            paths,
+            &config.templates,
+            &mut metadata,
+        )?;
+        apply_patches(
+            &target_dir,
+            canonical_path,
+            paths,
+            &config.templates,
+            &mut metadata,
+        )?;
     } else {
         // Update code from upstream
         ensure_git_clean(&paths.root)?;
-        let version = get_update_version(&args, &metadata)?;
-
-        if version == metadata.version && !args.force {
-            bail!("Already on the specified version");
-        }
-
-        let repo_dir = target_dir.join("repo");
+        let repo_dir = paths.repo_dir(&target_dir);
         if !repo_dir.exists() && !args.force {
             bail!("Repo dir not found: {}", repo_dir.display());
         }
 
-        fs::remove_dir_all(&repo_dir)?;
+        // Fetch into a staging dir first, so a missing version doesn't leave
+        // the dependency without sources.
+        let staging_dir = target_dir.join(".upstream");
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir)?;
+        }
+        let new_revision = if metadata.archive.is_some() || args.archive.is_some() {
+            let (url, archive) = update_archive_source(&args, &metadata)?;
+            paths.config.upstream.check_url(&url)?;
+            let new_revision =
+                fetch_archive(&url, &archive, metadata.subdir.as_deref(), &staging_dir);
+            // The new source is committed together with the new upstream
+            // code, so abort restores the previous one.
+            metadata.url = url;
+            metadata.archive = Some(archive);
+            new_revision
+        } else {
+            paths.config.upstream.check_url(&metadata.url)?;
+            let version = get_update_version(args.version.as_deref(), &metadata)?;
 
-        let mut clone_cmd = Command::new("git");
-        clone_cmd.args(["clone", &metadata.url, repo_dir.to_str().unwrap()]);
-        run_command(clone_cmd, "clone", None).context("Failed to clone repo")?;
+            if version.commit.as_ref() == Some(&metadata.revision.resolved_commit) && !args.force {
+                bail!("Already on the specified version");
+            }
 
-        let mut checkout_version_cmd = Command::new("git");
-        checkout_version_cmd
-            .current_dir(&repo_dir)
-            .args(["checkout", &version]);
+            fetch_upstream(
+                &metadata.url,
+                version.requested_ref.as_deref(),
+                metadata.subdir.as_deref(),
+                &staging_dir,
+            )
+        };
+        let new_revision = new_revision.and_then(|mut new_revision| {
+            filter_upstream_tree(&staging_dir, &metadata.filter, &mut new_revision)?;
+            Ok(new_revision)
+        });
+        let new_revision = match new_revision {
+            Ok(new_revision)
+                if new_revision.resolved_commit != metadata.revision.resolved_commit
+                    || args.force =>
+            {
+                new_revision
+            }
+            res => {
+                if staging_dir.exists() {
+                    fs::remove_dir_all(&staging_dir)?;
+                }
+                res?;
+                bail!("Already on the specified version");
+            }
+        };
+
+        let prev_commit_hash = get_current_commit(&paths.root)?;
+        let mut branch = None;
+        let mut original_branch = None;
+        // Options given on the command line override conflicting defaults
+        let no_commit = args.no_commit || (config.update.no_commit && !args.squash && !args.branch);
+        let squash = args.squash || (config.update.squash && !no_commit);
+        if (args.branch || config.update.branch) && !args.no_branch && !no_commit {
+            let new_branch = update_branch_name(&target_dir, &new_revision);
+            original_branch = current_branch(&paths.root)?;
+            if let Err(err) = git_output(&paths.root, &["switch", "--quiet", "-c", &new_branch]) {
+                fs::remove_dir_all(&staging_dir)?;
+                return Err(err);
+            }
+            branch = Some(new_branch);
+        }
 
-        fs::remove_dir_all(repo_dir.join(".git"))?;
+        if repo_dir.exists() {
+            fs::remove_dir_all(&repo_dir)?;
+        }
+        fs::rename(&staging_dir, &repo_dir)?;
+
+        let mut upstream_shortlog = vec![];
+        if squash && metadata.archive.is_none() {
+            upstream_shortlog = upstream_shortlog_or_warn(
+                &metadata.url,
+                &metadata.revision.resolved_commit,
+                &new_revision.resolved_commit,
+            );
+        }
 
         metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: version.clone(),
-            patches: load_patch_list(&target_dir)?
+            prev_commit_hash,
+            new_revision,
+            branch,
+            original_branch,
+            squash,
+            upstream_shortlog,
+            no_commit,
+            snapshot_tree: if no_commit {
+                Some(write_tree(&paths.root)?)
+            } else {
+                None
+            },
+            checkpoint_tree: None,
+            edited_patch: None,
+            patches: load_patch_list(paths, &target_dir)?
                 .iter()
                 .map(|e| PatchApplyState {
                     name: e.clone(),
@@ -240,51 +1098,250 @@ pub fn update(args: UpdateCommandArgs, paths: &paths::MonorepoPaths) -> Result<(
                 })
                 .collect(),
         });
-        update_metadata(&target_dir, &metadata)?;
+        update_metadata(paths, &target_dir, &metadata)?;
+
+        let commit_msg =
+            update_commit_message(&config.templates.import, canonical_path, &metadata, None)?;
+        commit_update_step(&commit_msg, &target_dir, paths, &mut metadata)?;
+
+        apply_patches(
+            &target_dir,
+            canonical_path,
+            paths,
+            &config.templates,
+            &mut metadata,
+        )?;
+    }
 
-        let commit_message = format!("Update {} to {}", &canonical_path, version);
-        commit_code(&commit_message, &paths.root)?;
+    finish_update(&target_dir, canonical_path, paths, metadata)
+}
 
-        apply_patches(&target_dir, &canonical_path, paths, &mut metadata)?;
-    }
+/// Completes the update once all patches are applied: records the new
+/// revision and commits it, or stages it in no-commit mode.
+fn finish_update(
+    target_dir: &Path,
+    canonical_path: &str,
+    paths: &MonorepoPaths,
+    mut metadata: DependencyMetadata,
+) -> Result<()> {
+    let commit_msg = update_commit_message(
+        &paths.config.templates.metadata_updated,
+        canonical_path,
+        &metadata,
+        None,
+    )?;
+    let update_state = metadata.update_state.take().unwrap();
+    let prev_revision = metadata.revision.clone();
+    metadata.revision = update_state.new_revision.clone();
+    metadata.patched_tree_hash = Some(hash_tree(&paths.repo_dir(target_dir))?);
+    update_metadata(paths, target_dir, &metadata)?;
 
-    metadata.version = metadata.update_state.unwrap().new_version;
-    metadata.update_state = None;
-    update_metadata(&target_dir, &metadata)?;
+    println!("All patches were applied");
+    if update_state.no_commit {
+        stage_dir(target_dir, &paths.root)?;
+        println!("The update of {canonical_path} is staged, review and commit it");
+        return Ok(());
+    }
 
-    let commit_msg = format!("Update metadata for {}", &canonical_path);
     commit_code(&commit_msg, &paths.root)?;
-    println!("All patches were applied");
+    if update_state.squash {
+        git_output(
+            &paths.root,
+            &["reset", "--soft", &update_state.prev_commit_hash],
+        )?;
+        let prev_tree = git_output(
+            &paths.root,
+            &[
+                "rev-parse",
+                &format!("{}^{{tree}}", update_state.prev_commit_hash),
+            ],
+        )?;
+        if write_tree(&paths.root)? == prev_tree.trim() {
+            // E.g. a patch edit that didn't change anything
+            println!("Nothing changed, no commit was made");
+            return Ok(());
+        }
+        let commit_msg = squash_message(canonical_path, &prev_revision, &update_state);
+        commit_code(&commit_msg, &paths.root)?;
+        println!("Squashed the update into a single commit");
+    }
+    if let Some(branch) = update_state.branch {
+        match update_state.original_branch {
+            Some(original_branch) => println!(
+                "The update is committed to branch {branch}, merge it into {original_branch}"
+            ),
+            None => println!("The update is committed to branch {branch}"),
+        }
+    }
     Ok(())
 }
 
-fn apply_patches(
-    target_dir: &PathBuf,
+/// Message of the squashed update commit: versions, patches and upstream
+/// changes.
+fn squash_message(
     canonical_path: &str,
-    paths: &MonorepoPaths,
-    metadata: &mut DependencyMetadata,
-) -> Result<()> {
-    let mut update_state_mut = metadata.update_state.clone().unwrap();
+    prev_revision: &UpstreamRevision,
+    update_state: &UpdateState,
+) -> String {
+    let mut message = match update_state.edited_patch {
+        Some(ref patch) => format!("Edit patch {} of {}\n", patch, canonical_path),
+        None => format!(
+            "Update {} from {} to {}\n",
+            canonical_path, prev_revision, update_state.new_revision
+        ),
+    };
+    if !update_state.patches.is_empty() {
+        message.push_str("\nPatches:\n");
+        for patch in update_state.patches.iter() {
+            message.push_str(&format!("  {} - {}\n", patch.name, patch.state));
+        }
+    }
+    if !update_state.upstream_shortlog.is_empty() {
+        message.push_str("\nUpstream changes:\n");
+        for line in update_state.upstream_shortlog.iter() {
+            message.push_str(&format!("  {line}\n"));
+        }
+    }
 
-    if let Some(ref update_state) = metadata.clone().update_state {
-        let patches_count = update_state.patches.len();
-        println!("\nApplying patches:");
-        for (idx, patch) in update_state.patches.clone().iter().enumerate() {
-            match patch.state {
-                PatchState::Pending => {
-                    match try_apply_patch(target_dir, paths, &patch.name) {
-                        Ok(_) => {
-                            update_state_mut.patches[idx].state = PatchState::Applied;
-                            metadata.update_state = Some(update_state_mut.clone());
-                            update_metadata(target_dir, metadata)?;
-                            let commit_msg = format!(
-                                "Applied patch ({}/{}) {} for {}",
-                                idx + 1,
-                                patches_count,
-                                patch.name,
-                                &canonical_path,
-                            );
-                            commit_code(&commit_msg, &paths.root)?;
+    message
+}
+
+/// Lists upstream commits in `from..to` as `<short hash> <subject>`. Upstream
+/// is cloned without trees and blobs, so only the history is fetched.
+fn upstream_shortlog(url: &str, from: &str, to: &str) -> Result<Vec<String>> {
+    let scratch_dir = tempfile::tempdir()?;
+    let clone_dir = scratch_dir.path().join("repo");
+    git_output(
+        scratch_dir.path(),
+        &[
+            "clone",
+            "--quiet",
+            "--bare",
+            "--filter=tree:0",
+            url,
+            clone_dir.to_str().unwrap(),
+        ],
+    )?;
+
+    let range = format!("{from}..{to}");
+    Ok(git_output(&clone_dir, &["log", "--format=%h %s", &range])?
+        .lines()
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Like `upstream_shortlog`, but the shortlog is optional for the squashed
+/// commit, e.g. upstream history may be rewritten, so errors are reported
+/// and an empty list is returned.
+fn upstream_shortlog_or_warn(url: &str, from: &str, to: &str) -> Vec<String> {
+    upstream_shortlog(url, from, to).unwrap_or_else(|err| {
+        println!("Warning: could not get upstream changes since {from}: {err:#}");
+        vec![]
+    })
+}
+
+/// Name of the branch for the update of the dependency in `target_dir` to
+/// `revision`, e.g. `dockyard/update/zlib-v1.3`.
+fn update_branch_name(target_dir: &Path, revision: &UpstreamRevision) -> String {
+    let version = match revision.requested_ref {
+        Some(ref requested_ref) => requested_ref.as_str(),
+        None => {
+            let commit = &revision.resolved_commit;
+            let commit = commit.strip_prefix("sha256:").unwrap_or(commit);
+            &commit[..commit.len().min(12)]
+        }
+    };
+    let name = format!(
+        "{}-{}",
+        target_dir.file_name().unwrap().to_string_lossy(),
+        version
+    );
+    // Keep the name a valid ref, e.g. refs can't contain spaces or colons
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' | '/' => c,
+            _ => '-',
+        })
+        .collect();
+
+    format!("dockyard/update/{name}")
+}
+
+/// Returns the checked out branch, `None` if HEAD is detached.
+fn current_branch(current_dir: &Path) -> Result<Option<String>> {
+    let branch_cmd = Command::new("git")
+        .current_dir(current_dir)
+        .args(["symbolic-ref", "--quiet", "--short", "HEAD"])
+        .output()?;
+    if !branch_cmd.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        String::from_utf8(branch_cmd.stdout)?.trim().to_string(),
+    ))
+}
+
+fn ensure_on_update_branch(state: &UpdateState, current_dir: &Path) -> Result<()> {
+    if let Some(ref branch) = state.branch
+        && current_branch(current_dir)?.as_ref() != Some(branch)
+    {
+        bail!(
+            "The update is committed to branch {}, switch to it first",
+            branch
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs git command and returns its stdout.
+fn git_output(current_dir: &Path, args: &[&str]) -> Result<String> {
+    let git_cmd = Command::new("git")
+        .current_dir(current_dir)
+        .args(args)
+        .output()?;
+    if !git_cmd.status.success() {
+        bail!(
+            "git {} failed, stdout: {}, stderr: {}",
+            args[0],
+            String::from_utf8_lossy(&git_cmd.stdout),
+            String::from_utf8_lossy(&git_cmd.stderr),
+        );
+    }
+
+    Ok(String::from_utf8(git_cmd.stdout)?)
+}
+
+fn apply_patches(
+    target_dir: &Path,
+    canonical_path: &str,
+    paths: &MonorepoPaths,
+    templates: &TemplatesConfig,
+    metadata: &mut DependencyMetadata,
+) -> Result<()> {
+    let mut update_state_mut = metadata.update_state.clone().unwrap();
+
+    if let Some(ref update_state) = metadata.clone().update_state {
+        let patches_count = update_state.patches.len();
+        println!("\nApplying patches:");
+        for (idx, patch) in update_state.patches.clone().iter().enumerate() {
+            match patch.state {
+                PatchState::Pending => {
+                    match try_apply_patch(target_dir, paths, &patch.name) {
+                        Ok(_) => {
+                            update_state_mut.patches[idx].state = PatchState::Applied;
+                            metadata.update_state = Some(update_state_mut.clone());
+                            update_metadata(paths, target_dir, metadata)?;
+                            let commit_msg = update_commit_message(
+                                &templates.patch_applied,
+                                canonical_path,
+                                metadata,
+                                Some(idx),
+                            )?;
+                            commit_update_step(&commit_msg, target_dir, paths, metadata)?;
+                            update_state_mut = metadata.update_state.clone().unwrap();
                             println!(
                                 "Successfully applied patch ({}/{}) {} for {}",
                                 idx + 1,
@@ -296,7 +1353,7 @@ fn apply_patches(
                         Err(_) => {
                             update_state_mut.patches[idx].state = PatchState::Conflict;
                             metadata.update_state = Some(update_state_mut.clone());
-                            update_metadata(target_dir, metadata)?;
+                            update_metadata(paths, target_dir, metadata)?;
 
                             let relative_target_path = target_dir.strip_prefix(&paths.root)?;
                             print!(
@@ -305,7 +1362,7 @@ fn apply_patches(
 1. Try to apply with rejected hunks:
 
   cd {}
-  git apply --reject --directory={}/repo ../patches/{}
+  git apply --reject --directory={}/{} {}/{}
 
 2. Check *.rej files and apply conflicted hunks manually in source files (not in patch).
 3. Run the following command
@@ -317,6 +1374,8 @@ It'll refresh the current patch and will continue with subsequent patches.
 ",
                                 relative_target_path.display(),
                                 relative_target_path.display(),
+                                paths.config.layout.repo,
+                                paths.config.layout.patches,
                                 patch.name,
                                 canonical_path
                             );
@@ -328,29 +1387,37 @@ It'll refresh the current patch and will continue with subsequent patches.
                     println!("Skipping already applied patch {}", patch.name);
                 }
                 PatchState::Conflict => {
-                    let repo_dir = target_dir.join("repo");
-                    let diff = extract_diff(&repo_dir, paths)?;
+                    let repo_dir = paths.repo_dir(target_dir);
+                    let diff = extract_diff(&repo_dir, update_state_mut.base_rev(), paths)?;
 
-                    let patches_dir = target_dir.join("patches");
+                    let patches_dir = paths.patches_dir(target_dir);
                     let patch_path = patches_dir.join(&patch.name);
+                    let prev_patch = fs::read(&patch_path).unwrap_or_default();
 
                     let mut file = File::create(&patch_path)?;
+                    file.write_all(patch_header(&prev_patch))?;
                     file.write_all(&diff)?;
 
                     println!("Patch {} updated", patch_path.display());
 
                     update_state_mut.patches[idx].state = PatchState::Resolved;
                     metadata.update_state = Some(update_state_mut.clone());
-                    update_metadata(target_dir, metadata)?;
-                    let commit_msg = format!(
-                        "Resolve conflicted patch ({}/{}) {} for {}",
-                        idx, patches_count, patch.name, &canonical_path,
-                    );
-                    commit_code(&commit_msg, &paths.root)?;
+                    update_metadata(paths, target_dir, metadata)?;
+                    let commit_msg = update_commit_message(
+                        &templates.conflict_resolved,
+                        canonical_path,
+                        metadata,
+                        Some(idx),
+                    )?;
+                    commit_update_step(&commit_msg, target_dir, paths, metadata)?;
+                    update_state_mut = metadata.update_state.clone().unwrap();
                 }
                 PatchState::Resolved => {
                     println!("Skipping already applied patch {}", patch.name);
                 }
+                PatchState::Skipped => {
+                    println!("Skipping dropped patch {}", patch.name);
+                }
             };
         }
         Ok(())
@@ -359,14 +1426,66 @@ It'll refresh the current patch and will continue with subsequent patches.
     }
 }
 
+/// Drops the conflicted patch from the update: restores the repo dir to the
+/// pre-patch tree, removes the patch file and commits the removal.
+fn skip_conflicted_patch(
+    target_dir: &Path,
+    canonical_path: &str,
+    paths: &MonorepoPaths,
+    templates: &TemplatesConfig,
+    metadata: &mut DependencyMetadata,
+) -> Result<()> {
+    let mut update_state = metadata.update_state.clone().unwrap();
+    let patches_count = update_state.patches.len();
+
+    let Some(idx) = update_state
+        .patches
+        .iter()
+        .position(|p| p.state == PatchState::Conflict)
+    else {
+        bail!("No conflicted patch to skip");
+    };
+    let patch_name = update_state.patches[idx].name.clone();
+
+    let repo_dir = paths.repo_dir(target_dir);
+    restore_dir(&repo_dir, update_state.base_rev(), &paths.root)?;
+
+    let patch_path = paths.patches_dir(target_dir).join(&patch_name);
+    if patch_path.exists() {
+        fs::remove_file(&patch_path)?;
+    }
+    remove_from_series(paths, target_dir, &patch_name)?;
+
+    update_state.patches[idx].state = PatchState::Skipped;
+    metadata.update_state = Some(update_state);
+    update_metadata(paths, target_dir, metadata)?;
+
+    let commit_msg = update_commit_message(
+        &templates.patch_skipped,
+        canonical_path,
+        metadata,
+        Some(idx),
+    )?;
+    commit_update_step(&commit_msg, target_dir, paths, metadata)?;
+    println!(
+        "Skipped patch ({}/{}) {} for {}",
+        idx + 1,
+        patches_count,
+        patch_name,
+        canonical_path,
+    );
+
+    Ok(())
+}
+
 fn try_apply_patch(
-    target_dir: &PathBuf,
+    target_dir: &Path,
     paths: &paths::MonorepoPaths,
     patch_name: &str,
 ) -> Result<()> {
-    let patches_dir = target_dir.join("patches");
-    let repo_dir = target_dir.join("repo");
-    let patch_path = patches_dir.join(&patch_name);
+    let patches_dir = paths.patches_dir(target_dir);
+    let repo_dir = paths.repo_dir(target_dir);
+    let patch_path = patches_dir.join(patch_name);
     let relative_path = repo_dir.strip_prefix(&paths.root)?;
     let relative_path = relative_path.to_string_lossy().replace('\\', "/");
 
@@ -401,8 +1520,82 @@ fn git_add_all(current_dir: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Renders the commit message of an update step from `template`. `patch_idx`
+/// is the index of the patch the step is related to.
+fn update_commit_message(
+    template: &str,
+    canonical_path: &str,
+    metadata: &DependencyMetadata,
+    patch_idx: Option<usize>,
+) -> Result<String> {
+    let update_state = metadata.update_state.as_ref().unwrap();
+    let (patch, index) = match patch_idx {
+        Some(idx) => (
+            update_state.patches[idx].name.clone(),
+            (idx + 1).to_string(),
+        ),
+        None => (String::new(), String::new()),
+    };
+
+    render_template(
+        template,
+        &[
+            ("path", canonical_path),
+            ("old_version", &metadata.revision.to_string()),
+            ("new_version", &update_state.new_revision.to_string()),
+            ("patch", &patch),
+            ("index", &index),
+            ("count", &update_state.patches.len().to_string()),
+        ],
+    )
+}
+
+/// Records the completed update step. Commits it, or in no-commit mode stages
+/// the dependency dir and saves the staged tree as the new checkpoint.
+fn commit_update_step(
+    message: &str,
+    target_dir: &Path,
+    paths: &MonorepoPaths,
+    metadata: &mut DependencyMetadata,
+) -> Result<()> {
+    let root = &paths.root;
+    let update_state = metadata.update_state.as_mut().unwrap();
+    if !update_state.no_commit {
+        return commit_code(message, root);
+    }
+
+    stage_dir(target_dir, root)?;
+    update_state.checkpoint_tree = Some(write_tree(root)?);
+    update_metadata(paths, target_dir, metadata)?;
+    stage_dir(target_dir, root)
+}
+
+/// Stages all changes in `dir`, leaving the rest of the index as is.
+fn stage_dir(dir: &Path, current_dir: &Path) -> Result<()> {
+    let add_cmd = Command::new("git")
+        .current_dir(current_dir)
+        .args(["add", "--all", "--"])
+        .arg(dir)
+        .output()?;
+
+    if !add_cmd.status.success() {
+        bail!(
+            "git add failed, stdout: {}, stderr: {}",
+            String::from_utf8_lossy(&add_cmd.stdout),
+            String::from_utf8_lossy(&add_cmd.stderr),
+        );
+    }
+
+    Ok(())
+}
+
+/// Saves the index as a tree object and returns its hash.
+fn write_tree(current_dir: &Path) -> Result<String> {
+    Ok(git_output(current_dir, &["write-tree"])?.trim().to_string())
+}
+
 fn commit_code(message: &str, current_dir: &Path) -> Result<()> {
-    git_add_all(&current_dir)?;
+    git_add_all(current_dir)?;
 
     let commit_cmd = Command::new("git")
         .current_dir(current_dir)
@@ -434,7 +1627,7 @@ fn ensure_git_clean(current_dir: &Path) -> Result<()> {
         );
     }
 
-    if git_cmd.stdout.len() > 0 {
+    if !git_cmd.stdout.is_empty() {
         bail!(
             "git must be clean, but has changes:\n {}",
             String::from_utf8_lossy(&git_cmd.stdout),
@@ -444,88 +1637,544 @@ fn ensure_git_clean(current_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-fn revert_to_commit(hash: &str, current_dir: &Path) -> Result<()> {
-    git_add_all(&current_dir)?;
+/// Undoes the update of the dependency in `target_dir` started at commit
+/// `prev_commit_hash`: drops commits made since then and restores the
+/// dependency dir to its previous state. Refuses to proceed if the commits or
+/// uncommitted changes touch anything outside the dependency dir.
+fn abort_update(target_dir: &Path, prev_commit_hash: &str, root: &Path) -> Result<()> {
+    let target_path = target_dir.strip_prefix(root)?;
+    let is_ancestor_cmd = Command::new("git")
+        .current_dir(root)
+        .args(["merge-base", "--is-ancestor", prev_commit_hash, "HEAD"])
+        .output()?;
+    if !is_ancestor_cmd.status.success() {
+        bail!(
+            "Commit {} the update started from isn't an ancestor of HEAD",
+            prev_commit_hash
+        );
+    }
 
-    let commit_cmd = Command::new("git")
+    let range = format!("{prev_commit_hash}..HEAD");
+    let unrelated_commits: Vec<_> =
+        git_changed_files(root, &["log", "--format=", "--name-only", &range])?
+            .into_iter()
+            .filter(|file| !file.starts_with(target_path))
+            .collect();
+    if !unrelated_commits.is_empty() {
+        bail!(
+            "Commits since {} change files outside {}, revert them first:\n{}",
+            prev_commit_hash,
+            target_path.display(),
+            files_list(&unrelated_commits),
+        );
+    }
+
+    let mut unrelated_changes = git_changed_files(root, &["diff", "--name-only", "HEAD"])?;
+    unrelated_changes.extend(git_changed_files(
+        root,
+        &["diff", "--name-only", "--cached"],
+    )?);
+    unrelated_changes.retain(|file| !file.starts_with(target_path));
+    unrelated_changes.sort();
+    unrelated_changes.dedup();
+    if !unrelated_changes.is_empty() {
+        bail!(
+            "Uncommitted changes outside {}, commit or stash them first:\n{}",
+            target_path.display(),
+            files_list(&unrelated_changes),
+        );
+    }
+
+    // Everything outside the dependency dir is the same as in the previous
+    // commit, so restoring the dependency dir and moving HEAD back keeping
+    // the index and the work tree gives exactly the previous state.
+    restore_dir(target_dir, prev_commit_hash, root)?;
+
+    let reset_cmd = Command::new("git")
+        .current_dir(root)
+        .args(["reset", "--soft", prev_commit_hash])
+        .output()?;
+    if !reset_cmd.status.success() {
+        bail!(
+            "git reset failed, stdout: {}, stderr: {}",
+            String::from_utf8_lossy(&reset_cmd.stdout),
+            String::from_utf8_lossy(&reset_cmd.stderr),
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs git command printing paths relative to the repo root, e.g. `git diff
+/// --name-only`, and returns the paths.
+fn git_changed_files(current_dir: &Path, args: &[&str]) -> Result<Vec<PathBuf>> {
+    let git_cmd = Command::new("git")
         .current_dir(current_dir)
-        .args(["reset", "--hard", hash])
+        .args(args)
+        .arg("-z")
         .output()?;
+    if !git_cmd.status.success() {
+        bail!(
+            "git {} failed, stdout: {}, stderr: {}",
+            args[0],
+            String::from_utf8_lossy(&git_cmd.stdout),
+            String::from_utf8_lossy(&git_cmd.stderr),
+        );
+    }
 
-    if !commit_cmd.status.success() {
+    Ok(String::from_utf8(git_cmd.stdout)?
+        .split(['\0', '\n'])
+        .filter(|file| !file.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn files_list(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| format!("  {}", file.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Restores `dir` (both index and working tree) to its state in `source` and
+/// removes untracked files left in it, e.g. `*.rej` files.
+fn restore_dir(dir: &Path, source: &str, current_dir: &Path) -> Result<()> {
+    let source_arg = format!("--source={source}");
+    let restore_cmd = Command::new("git")
+        .current_dir(current_dir)
+        .args(["restore", &source_arg, "--staged", "--worktree", "--"])
+        .arg(dir)
+        .output()?;
+
+    if !restore_cmd.status.success() {
         bail!(
-            "git reset failed, stdout: {}, stderr: {}",
-            String::from_utf8_lossy(&commit_cmd.stdout),
-            String::from_utf8_lossy(&commit_cmd.stderr),
+            "git restore failed, stdout: {}, stderr: {}",
+            String::from_utf8_lossy(&restore_cmd.stdout),
+            String::from_utf8_lossy(&restore_cmd.stderr),
+        );
+    }
+
+    let clean_cmd = Command::new("git")
+        .current_dir(current_dir)
+        .args(["clean", "-fd", "--"])
+        .arg(dir)
+        .output()?;
+
+    if !clean_cmd.status.success() {
+        bail!(
+            "git clean failed, stdout: {}, stderr: {}",
+            String::from_utf8_lossy(&clean_cmd.stdout),
+            String::from_utf8_lossy(&clean_cmd.stderr),
         );
     }
 
     Ok(())
 }
 
-fn load_patch_list(target_dir: &PathBuf) -> Result<Vec<String>> {
-    let patches_dir = target_dir.join("patches");
+/// Optional quilt-style file in the patches dir listing patches in the order
+/// they are applied.
+const SERIES_FILE: &str = "series";
+
+/// Entry of the series file. Disabled entries are commented out patch names,
+/// e.g. `# 0002-fix.patch`, they are kept in the patches dir but not applied.
+/// A commented out word counts as a patch name only if it ends with `.patch`
+/// or `.diff` or such file exists in the patches dir, e.g. `# 3-way` is a
+/// plain comment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SeriesEntry {
+    name: String,
+    enabled: bool,
+}
 
-    let mut patches = Vec::new();
+/// Parses the series file of `patches_dir`: a patch name per line, `#` starts
+/// a comment, blank lines are ignored.
+fn parse_series(content: &str, patches_dir: &Path) -> Result<Vec<SeriesEntry>> {
+    let is_disabled_patch = |name: &str| {
+        check_patch_name(name).is_ok()
+            && (name.ends_with(".patch")
+                || name.ends_with(".diff")
+                || patches_dir.join(name).is_file())
+    };
+
+    let mut entries: Vec<SeriesEntry> = Vec::new();
+    for (line_idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        let (entry, enabled) = match line.strip_prefix('#') {
+            Some(comment) => match comment.split_whitespace().collect::<Vec<_>>()[..] {
+                [name] if is_disabled_patch(name) => (name, false),
+                _ => continue,
+            },
+            None => {
+                let (entry, _) = split_series_comment(line);
+                match entry.split_whitespace().collect::<Vec<_>>()[..] {
+                    [] => continue,
+                    [name] => (name, true),
+                    [name, ..] => bail!(
+                        "line {}: patch options aren't supported, got {:?} for {}",
+                        line_idx + 1,
+                        entry[name.len()..].trim(),
+                        name
+                    ),
+                }
+            }
+        };
+
+        check_patch_name(entry).with_context(|| format!("line {}", line_idx + 1))?;
+        if entries.iter().any(|e| e.name == entry) {
+            bail!("line {}: patch {} is listed twice", line_idx + 1, entry);
+        }
+        entries.push(SeriesEntry {
+            name: entry.to_string(),
+            enabled,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Splits a series line into the entry and its trailing comment, which starts
+/// with the whitespace before `#`, e.g. `("0001-fix.patch", "  # why")`. A
+/// `#` at the line start comments out the whole line.
+fn split_series_comment(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    let entry_len = line
+        .char_indices()
+        .find(|&(idx, c)| c == '#' && (idx == 0 || line[..idx].ends_with(char::is_whitespace)))
+        .map_or(line.len(), |(idx, _)| line[..idx].trim_end().len());
+    line.split_at(entry_len)
+}
+
+fn check_patch_name(name: &str) -> Result<()> {
+    if name == "." || name == ".." || name == SERIES_FILE || name.contains(['/', '\\']) {
+        bail!(
+            "invalid patch name {:?}, must be a file in the patches dir",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// Loads the series file of the dependency, `None` if there is no such file.
+fn load_series(paths: &MonorepoPaths, target_dir: &Path) -> Result<Option<Vec<SeriesEntry>>> {
+    let series_path = paths.patches_dir(target_dir).join(SERIES_FILE);
+    if !series_path.exists() {
+        return Ok(None);
+    }
+
+    let content = fs::read_to_string(&series_path)?;
+    let entries = parse_series(&content, &paths.patches_dir(target_dir))
+        .with_context(|| format!("Invalid {}", series_path.display()))?;
+
+    Ok(Some(entries))
+}
+
+/// Adds the enabled patch to the end of the series file if there is one.
+fn append_to_series(paths: &MonorepoPaths, target_dir: &Path, patch_name: &str) -> Result<()> {
+    let series_path = paths.patches_dir(target_dir).join(SERIES_FILE);
+    if !series_path.exists() {
+        return Ok(());
+    }
+
+    let mut content = fs::read_to_string(&series_path)?;
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(patch_name);
+    content.push('\n');
+    fs::write(&series_path, content)?;
+
+    Ok(())
+}
+
+/// Removes the enabled patch from the series file if there is one. Comments
+/// and other entries are kept as is.
+fn remove_from_series(paths: &MonorepoPaths, target_dir: &Path, patch_name: &str) -> Result<()> {
+    let series_path = paths.patches_dir(target_dir).join(SERIES_FILE);
+    if !series_path.exists() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&series_path)?;
+    let mut new_content = String::new();
+    for line in content.lines() {
+        let (entry, _) = split_series_comment(line);
+        if entry != patch_name {
+            new_content.push_str(line);
+            new_content.push('\n');
+        }
+    }
+    fs::write(&series_path, new_content)?;
+
+    Ok(())
+}
+
+/// Number the patch name starts with, e.g. 1 for `0001-fix-build.patch`.
+fn patch_number(name: &str) -> Option<u32> {
+    let (number, rest) = name.split_once('-')?;
+    if rest.is_empty() {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// Whether the file in the patches dir is a patch: a `*.patch` or `*.diff`
+/// file, or a numbered one like `0003-fix`.
+fn is_patch_file(name: &str) -> bool {
+    name.ends_with(".patch") || name.ends_with(".diff") || patch_number(name).is_some()
+}
+
+/// Returns enabled patches of the dependency in the order they are applied.
+/// The order comes from the series file if there is one, otherwise from the
+/// number the patch names start with. Other files, like a README, are ignored.
+fn load_patch_list(paths: &MonorepoPaths, target_dir: &Path) -> Result<Vec<String>> {
+    let patches_dir = paths.patches_dir(target_dir);
+
+    if !patches_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut patch_files = Vec::new();
     for entry in fs::read_dir(&patches_dir)? {
         let entry = entry?;
-        let fname = entry.file_name().into_string().unwrap();
-        if let Some(n_str) = fname.split('-').next() {
-            if let Ok(n) = n_str.parse::<u32>() {
-                patches.push((n, fname));
-            } else {
-                bail!("");
+        let Ok(fname) = entry.file_name().into_string() else {
+            bail!("Patch name isn't valid UTF-8: {}", entry.path().display());
+        };
+        if fname != SERIES_FILE && is_patch_file(&fname) && entry.file_type()?.is_file() {
+            patch_files.push(fname);
+        }
+    }
+
+    if let Some(series) = load_series(paths, target_dir)? {
+        for entry in series.iter() {
+            if !patches_dir.join(&entry.name).is_file() {
+                bail!(
+                    "Patch {} listed in {} doesn't exist",
+                    entry.name,
+                    patches_dir.join(SERIES_FILE).display()
+                );
+            }
+        }
+        for fname in patch_files.iter() {
+            if !series.iter().any(|e| &e.name == fname) {
+                bail!(
+                    "Patch {} isn't listed in {}, add it or disable it with `# {}`",
+                    fname,
+                    patches_dir.join(SERIES_FILE).display(),
+                    fname
+                );
             }
-        } else {
-            bail!("");
+        }
+
+        return Ok(series
+            .into_iter()
+            .filter(|e| e.enabled)
+            .map(|e| e.name)
+            .collect());
+    }
+
+    let mut patches = Vec::new();
+    for fname in patch_files {
+        match patch_number(&fname) {
+            Some(n) => patches.push((n, fname)),
+            None => bail!(
+                "Patch name {} must start with a number, e.g. 0001-fix-build.patch, \
+                 or the patch must be listed in {}",
+                fname,
+                patches_dir.join(SERIES_FILE).display()
+            ),
         }
     }
 
-    patches.sort_by(|a, b| a.0.cmp(&b.0));
-    Ok(patches.iter().map(|e| e.1.clone()).collect())
+    patches.sort();
+    Ok(patches.into_iter().map(|e| e.1).collect())
 }
 
 pub fn extract_patch(args: ExtractPatchCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
-    let target_dir = path_to_abs(paths, &args.path)?;
+    let target_dir = paths
+        .resolve_dependency(args.path.as_ref())?
+        .to_abs(paths)?;
 
     if !target_dir.exists() {
         return Err(anyhow!("Target doesn't exists: {}", target_dir.display()));
     }
 
-    let repo_dir = target_dir.join("repo");
-    let patches_dir = target_dir.join("patches");
+    let repo_dir = paths.repo_dir(&target_dir);
+    let patches_dir = paths.patches_dir(&target_dir);
     if !patches_dir.exists() {
         fs::create_dir_all(&patches_dir)?;
     }
 
-    let diff = extract_diff(&repo_dir, paths)?;
+    let (title, description) = match (args.name, args.message) {
+        (Some(name), message) => (Some(name), message),
+        (None, Some(message)) => match message.trim().split_once('\n') {
+            Some((title, description)) => (Some(title.to_string()), Some(description.to_string())),
+            None => (Some(message), None),
+        },
+        (None, None) => (None, None),
+    };
+    let slug = match &title {
+        Some(title) => slugify(title)
+            .ok_or_else(|| anyhow!("Patch name must contain letters or digits: {title:?}"))?,
+        None => "change_name".to_string(),
+    };
+
+    let diff = extract_diff(&repo_dir, "HEAD", paths)?;
+
+    // Fail early on malformed patches or series
+    load_patch_list(paths, &target_dir)?;
 
     // Determine patch number
     let mut max_n = 0;
     for entry in fs::read_dir(&patches_dir)? {
         let entry = entry?;
-        let fname = entry.file_name().into_string().unwrap();
-        if let Some(n_str) = fname.split('-').next() {
-            if let Ok(n) = n_str.parse::<u32>() {
-                if n > max_n {
-                    max_n = n;
-                }
-            }
+        let fname = entry.file_name().to_string_lossy().to_string();
+        if let Some(n_str) = fname.split('-').next()
+            && let Ok(n) = n_str.parse::<u32>()
+            && n > max_n
+        {
+            max_n = n;
         }
     }
     let patch_number = format!("{:04}", max_n + 1);
-    let patch_name = format!("{patch_number}-change_name.patch");
-    let patch_path = patches_dir.join(patch_name);
+    let patch_name = format!("{patch_number}-{slug}.patch");
+    let patch_path = patches_dir.join(&patch_name);
 
     let mut file = File::create(&patch_path)?;
+    if let Some(title) = title {
+        let header = format_patch_header(&paths.root, &title, description.as_deref())?;
+        file.write_all(header.as_bytes())?;
+    }
     file.write_all(&diff)?;
+    append_to_series(paths, &target_dir, &patch_name)?;
+
+    let mut metadata = load_metadata(paths, &target_dir)?;
+    metadata.patched_tree_hash = Some(hash_tree(&repo_dir)?);
+    update_metadata(paths, &target_dir, &metadata)?;
 
     println!("Patch written to: {}", patch_path.display());
 
     Ok(())
 }
 
-fn extract_diff(repo_dir: &PathBuf, paths: &paths::MonorepoPaths) -> Result<Vec<u8>> {
+/// Longest slug in patch file names, like in `git format-patch`.
+const MAX_SLUG_LEN: usize = 52;
+
+/// Turns patch title into a file name part: lowercase ASCII letters and digits
+/// separated by dashes. Returns `None` if nothing is left.
+fn slugify(title: &str) -> Option<String> {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LEN);
+    let slug = slug.trim_end_matches('-');
+
+    (!slug.is_empty()).then(|| slug.to_string())
+}
+
+/// Header in `git format-patch` format written above the diff. The author and
+/// date are taken from git, like for a commit.
+fn format_patch_header(root: &Path, title: &str, description: Option<&str>) -> Result<String> {
+    let ident = git_output(root, &["var", "GIT_AUTHOR_IDENT"])?;
+    format_patch_header_for(&ident, title, description)
+}
+
+/// Same as `format_patch_header`, but the author and date are taken from
+/// `ident` in the git format.
+fn format_patch_header_for(ident: &str, title: &str, description: Option<&str>) -> Result<String> {
+    // Name <email> 1700000000 +0100
+    let mut parts = ident.trim().rsplitn(3, ' ');
+    let (Some(tz), Some(timestamp), Some(author)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("Unexpected git author ident: {ident}");
+    };
+    let date = format_rfc2822(timestamp.parse()?, tz)?;
+
+    let mut header = format!(
+        "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n\
+         From: {author}\n\
+         Date: {date}\n\
+         Subject: [PATCH] {}\n\n",
+        title.trim()
+    );
+    if let Some(description) = description.map(str::trim).filter(|d| !d.is_empty()) {
+        header.push_str(description);
+        header.push_str("\n\n");
+    }
+    header.push_str("---\n");
+
+    Ok(header)
+}
+
+/// Formats unix `timestamp` in `tz` offset like `Tue, 3 Jun 2025 14:05:09 +0200`.
+fn format_rfc2822(timestamp: i64, tz: &str) -> Result<String> {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (sign, offset) = tz.split_at_checked(1).unwrap_or_default();
+    let offset: i64 = match (sign, offset.parse::<i64>()) {
+        ("+", Ok(offset)) if offset.to_string().len() <= 4 => offset,
+        ("-", Ok(offset)) if offset.to_string().len() <= 4 => -offset,
+        _ => bail!("Invalid timezone offset: {tz}"),
+    };
+    let local = timestamp + (offset / 100 * 60 + offset % 100) * 60;
+    let days = local.div_euclid(86400);
+    let secs = local.rem_euclid(86400);
+
+    // Civil date from days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    Ok(format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} {}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        tz
+    ))
+}
+
+/// Returns the header of a patch, everything above the diff. The diffstat
+/// after the `---` separator is dropped as it goes stale once the diff is
+/// refreshed.
+fn patch_header(patch: &[u8]) -> &[u8] {
+    let mut header_len = 0;
+    let mut separator_end = None;
+    for line in patch.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(b"diff ") || line.starts_with(b"--- ") || line.starts_with(b"Index: ") {
+            break;
+        }
+        header_len += line.len();
+        if line == b"---\n" {
+            separator_end = Some(header_len);
+        }
+    }
+
+    &patch[..separator_end.unwrap_or(header_len)]
+}
+
+/// Diffs `repo_dir` against `base` revision, both staged and unstaged changes.
+fn extract_diff(repo_dir: &Path, base: &str, paths: &paths::MonorepoPaths) -> Result<Vec<u8>> {
     let relative_path = repo_dir.strip_prefix(&paths.root)?;
 
     let repo_dir = repo_dir.to_string_lossy().replace('\\', "/");
@@ -551,8 +2200,9 @@ fn extract_diff(repo_dir: &PathBuf, paths: &paths::MonorepoPaths) -> Result<Vec<
         .current_dir(&paths.root)
         .args([
             "diff".to_string(),
+            "--binary".to_string(),
             // include all files (from index and unstaged)
-            "HEAD".to_string(),
+            base.to_string(),
             format!("--relative={}", &relative_path),
             "--".to_string(),
             repo_dir.clone(),
@@ -570,606 +2220,4437 @@ fn extract_diff(repo_dir: &PathBuf, paths: &paths::MonorepoPaths) -> Result<Vec<
     Ok(patch_cmd.stdout)
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{fs, path::Path, process::Command};
-
-    use anyhow::{Context, bail};
-    use dockyard::{paths::path_to_abs, *};
-    use tempfile::{TempDir, tempdir};
+/// Vendored dependency as shown by `dockyard list`.
+#[derive(Serialize, Debug)]
+struct DependencyInfo {
+    path: String,
+    /// Name of the third-party root the dependency is under.
+    root: String,
+    /// `None` if the metadata can't be loaded.
+    url: Option<String>,
+    #[serde(flatten)]
+    revision: Option<UpstreamRevision>,
+    patches: usize,
+    update_in_progress: bool,
+    error: Option<String>,
+}
 
-    use super::*;
+pub fn list(args: ListCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
+    let deps = load_dependencies(paths)?;
 
-    #[test]
-    fn test_extract_patch() -> anyhow::Result<()> {
-        let temp_dir = create_test_dir()?;
+    match args.format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&deps)?);
+        }
+        OutputFormat::Text => {
+            let path_width = deps.iter().map(|d| d.path.len()).max().unwrap_or(0);
+            let url_width = deps
+                .iter()
+                .filter_map(|d| d.url.as_ref())
+                .map(|url| url.len())
+                .max()
+                .unwrap_or(0);
+            for dep in &deps {
+                let (Some(url), Some(revision)) = (&dep.url, &dep.revision) else {
+                    println!(
+                        "{:path_width$}  error: {}",
+                        dep.path,
+                        dep.error.as_deref().unwrap_or_default()
+                    );
+                    continue;
+                };
+                println!(
+                    "{:path_width$}  {:url_width$}  {}  patches: {}{}",
+                    dep.path,
+                    url,
+                    revision,
+                    dep.patches,
+                    if dep.update_in_progress {
+                        "  (update in progress)"
+                    } else {
+                        ""
+                    },
+                );
+            }
+        }
+    }
+
+    let failed = deps.iter().filter(|d| d.error.is_some()).count();
+    if failed > 0 {
+        bail!("Failed to load {} dependencies", failed);
+    }
+
+    Ok(())
+}
+
+/// Loads the vendored dependencies. Failures specific to a dependency are
+/// reported in its info, so that the others are still listed.
+fn load_dependencies(paths: &paths::MonorepoPaths) -> Result<Vec<DependencyInfo>> {
+    let mut deps = Vec::new();
+    for target_dir in paths.find_dependencies()? {
+        let mut info = DependencyInfo {
+            path: CanonicalPath::from_abs(paths, &target_dir)?.to_string(),
+            root: paths
+                .root_of(&target_dir)
+                .map(|root| root.name.clone())
+                .unwrap_or_default(),
+            url: None,
+            revision: None,
+            patches: 0,
+            update_in_progress: false,
+            error: None,
+        };
+        let loaded = load_metadata(paths, &target_dir)
+            .and_then(|metadata| Ok((metadata, load_patch_list(paths, &target_dir)?.len())));
+        match loaded {
+            Ok((metadata, patches)) => {
+                info.update_in_progress = metadata.update_state.is_some();
+                info.url = Some(metadata.url);
+                info.revision = Some(metadata.revision);
+                info.patches = patches;
+            }
+            Err(err) => info.error = Some(format!("{err:#}")),
+        }
+        deps.push(info);
+    }
+
+    Ok(deps)
+}
+
+/// Upstream state of a vendored dependency as shown by `dockyard outdated`.
+#[derive(Serialize, Debug)]
+struct UpstreamStatus {
+    path: String,
+    /// `None` if the metadata can't be loaded.
+    current: Option<UpstreamRevision>,
+    available_ref: Option<String>,
+    available_commit: Option<String>,
+    outdated: bool,
+    /// Archive dependencies have no upstream to check.
+    archive: bool,
+    error: Option<String>,
+}
+
+/// Checks upstreams of the vendored dependencies. Returns `false` if any of
+/// them lags behind.
+pub fn outdated(args: OutdatedCommandArgs, paths: &paths::MonorepoPaths) -> Result<bool> {
+    let mut statuses = Vec::new();
+    for target_dir in dependency_dirs(paths, args.path.as_ref())? {
+        statuses.push(check_upstream(paths, &target_dir)?);
+    }
+
+    match args.format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&statuses)?);
+        }
+        OutputFormat::Text => {
+            let mut rows = vec![[
+                "PATH".to_string(),
+                "CURRENT".to_string(),
+                "AVAILABLE".to_string(),
+                "STATUS".to_string(),
+            ]];
+            for status in &statuses {
+                let available = match status.available_commit {
+                    Some(ref commit) => short_version(status.available_ref.as_deref(), commit),
+                    None => "-".to_string(),
+                };
+                let state = match status.error {
+                    Some(ref error) => format!("error: {error}"),
+                    None if status.archive => "unknown (archive)".to_string(),
+                    None if status.outdated => "outdated".to_string(),
+                    None => "up to date".to_string(),
+                };
+                let current = match status.current {
+                    Some(ref current) => {
+                        short_version(current.requested_ref.as_deref(), &current.resolved_commit)
+                    }
+                    None => "-".to_string(),
+                };
+                rows.push([status.path.clone(), current, available, state]);
+            }
+
+            let widths: Vec<_> = (0..3)
+                .map(|col| rows.iter().map(|r| r[col].len()).max().unwrap())
+                .collect();
+            for row in rows {
+                println!(
+                    "{:w0$}  {:w1$}  {:w2$}  {}",
+                    row[0],
+                    row[1],
+                    row[2],
+                    row[3],
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2],
+                );
+            }
+        }
+    }
+
+    let failed = statuses.iter().filter(|s| s.error.is_some()).count();
+    if failed > 0 {
+        bail!("Failed to check upstream of {} dependencies", failed);
+    }
+
+    Ok(!statuses.iter().any(|s| s.outdated))
+}
+
+/// Checks the upstream of the dependency. Failures specific to the
+/// dependency are reported in the status, so that other dependencies are
+/// still checked.
+fn check_upstream(paths: &paths::MonorepoPaths, target_dir: &Path) -> Result<UpstreamStatus> {
+    let mut status = UpstreamStatus {
+        path: CanonicalPath::from_abs(paths, target_dir)?.to_string(),
+        current: None,
+        available_ref: None,
+        available_commit: None,
+        outdated: false,
+        archive: false,
+        error: None,
+    };
+    let metadata = match load_metadata(paths, target_dir) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            status.error = Some(format!("{err:#}"));
+            return Ok(status);
+        }
+    };
+    status.current = Some(metadata.revision.clone());
+    status.archive = metadata.archive.is_some();
+    if let Err(err) = paths.config.upstream.check_url(&metadata.url) {
+        status.error = Some(err.to_string());
+        return Ok(status);
+    }
+    if status.archive {
+        return Ok(status);
+    }
+    match get_update_version(None, &metadata) {
+        Ok(version) => {
+            status.outdated = version
+                .commit
+                .as_ref()
+                .is_some_and(|c| *c != metadata.revision.resolved_commit);
+            status.available_ref = version.requested_ref;
+            status.available_commit = version.commit;
+        }
+        Err(err) => status.error = Some(err.to_string()),
+    }
+
+    Ok(status)
+}
+
+/// Formats version as `ref (short hash)` for tables.
+fn short_version(requested_ref: Option<&str>, commit: &str) -> String {
+    let short_commit = &commit[..commit.len().min(12)];
+    match requested_ref {
+        Some(requested_ref) if requested_ref != commit => {
+            format!("{requested_ref} ({short_commit})")
+        }
+        _ => short_commit.to_string(),
+    }
+}
+
+pub fn verify(args: VerifyCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
+    let mut drifted = 0;
+    for target_dir in dependency_dirs(paths, args.path.as_ref())? {
+        let canonical_path = CanonicalPath::from_abs(paths, &target_dir)?.to_string();
+
+        if args.offline {
+            let matches = verify_dependency_offline(paths, &target_dir)
+                .with_context(|| format!("Failed to verify {canonical_path}"))?;
+            if matches {
+                println!("{canonical_path}: OK");
+            } else {
+                drifted += 1;
+                println!("{canonical_path}: vendored code doesn't match the recorded tree hash");
+            }
+            continue;
+        }
+
+        let diffs = verify_dependency(paths, &target_dir)
+            .with_context(|| format!("Failed to verify {canonical_path}"))?;
+
+        if diffs.is_empty() {
+            println!("{canonical_path}: OK");
+        } else {
+            drifted += 1;
+            println!(
+                "{}: {} files drift from upstream with applied patches",
+                canonical_path,
+                diffs.len()
+            );
+            for diff in diffs {
+                println!("  {diff}");
+            }
+        }
+    }
+
+    if drifted > 0 {
+        bail!(
+            "{} dependencies have changes not recorded in patches, \
+            extract them with `dockyard extract-patch`",
+            drifted
+        );
+    }
+
+    Ok(())
+}
+
+/// Rebuilds the dependency from the recorded upstream commit and patches in a
+/// scratch dir and compares the result with the checked-in repo dir.
+fn verify_dependency(paths: &MonorepoPaths, target_dir: &Path) -> Result<Vec<TreeDiff>> {
+    let metadata = load_metadata(paths, target_dir)?;
+    if metadata.update_state.is_some() {
+        bail!("Update is in progress");
+    }
+
+    let scratch_dir = tempfile::tempdir()?;
+    let expected_dir = scratch_dir.path().join("repo");
+    fetch_recorded_upstream(paths, &metadata, &expected_dir)?;
+    apply_patch_series(
+        &expected_dir,
+        &paths.patches_dir(target_dir),
+        &load_patch_list(paths, target_dir)?,
+    )?;
+
+    Ok(diff_trees(&expected_dir, &paths.repo_dir(target_dir))?)
+}
+
+/// Fetches the upstream tree the dependency was vendored from, without
+/// patches, to `dest`.
+fn fetch_recorded_upstream(
+    paths: &MonorepoPaths,
+    metadata: &DependencyMetadata,
+    dest: &Path,
+) -> Result<()> {
+    paths.config.upstream.check_url(&metadata.url)?;
+
+    let mut revision = if let Some(ref archive) = metadata.archive {
+        fetch_archive(&metadata.url, archive, metadata.subdir.as_deref(), dest)?
+    } else {
+        fetch_upstream(
+            &metadata.url,
+            Some(&metadata.revision.resolved_commit),
+            metadata.subdir.as_deref(),
+            dest,
+        )?
+    };
+    filter_upstream_tree(dest, &metadata.filter, &mut revision)?;
+
+    Ok(())
+}
+
+/// Compares hash of the checked-in repo dir with the recorded one. Returns
+/// `true` if they match.
+fn verify_dependency_offline(paths: &MonorepoPaths, target_dir: &Path) -> Result<bool> {
+    let metadata = load_metadata(paths, target_dir)?;
+    if metadata.update_state.is_some() {
+        bail!("Update is in progress");
+    }
+    let Some(patched_tree_hash) = metadata.patched_tree_hash else {
+        bail!("No tree hash recorded, verify without --offline");
+    };
+
+    Ok(hash_tree(&paths.repo_dir(target_dir))? == patched_tree_hash)
+}
+
+/// Applies patches in order to the tree in `repo_dir` outside of monorepo.
+fn apply_patch_series(repo_dir: &Path, patches_dir: &Path, patches: &[String]) -> Result<()> {
+    // git apply resolves paths relative to the repository root, so make
+    // `repo_dir` a root of its own.
+    let mut init_cmd = Command::new("git");
+    init_cmd.current_dir(repo_dir).args(["init", "--quiet"]);
+    run_command(init_cmd, "git init", None)?;
+
+    for patch in patches {
+        apply_patch_file(repo_dir, patches_dir, patch)?;
+    }
+    fs::remove_dir_all(repo_dir.join(".git"))?;
+
+    Ok(())
+}
+
+/// Applies the patch to the root of git repo in `repo_dir`.
+fn apply_patch_file(repo_dir: &Path, patches_dir: &Path, patch: &str) -> Result<()> {
+    let apply_cmd = Command::new("git")
+        .current_dir(repo_dir)
+        .args(["apply", "--whitespace=nowarn"])
+        .arg(patches_dir.join(patch))
+        .output()?;
+    if !apply_cmd.status.success() {
+        bail!(
+            "Patch {} doesn't apply: {}",
+            patch,
+            String::from_utf8_lossy(&apply_cmd.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// Patch of a rewritten series. Its diff is regenerated from changes of the
+/// `sources` patches applied in order, the header is kept above the diff.
+struct SeriesPatch {
+    name: String,
+    sources: Vec<String>,
+    header: Vec<u8>,
+}
+
+pub fn patch(args: PatchCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
+    let dep_path = paths.resolve_dependency(args.path.as_ref())?;
+    let target_dir = dep_path.to_abs(paths)?;
+    let patches = load_patch_list(paths, &target_dir)?;
+
+    let mut series = Vec::new();
+    for name in patches.iter() {
+        let content = fs::read(paths.patches_dir(&target_dir).join(name))?;
+        series.push(SeriesPatch {
+            name: name.clone(),
+            sources: vec![name.clone()],
+            header: patch_header(&content).to_vec(),
+        });
+    }
+
+    let message = match args.command {
+        PatchCommand::List => return list_patches(paths, &target_dir, &series),
+        PatchCommand::Drop(args) => {
+            let idx = find_patch(&patches, &args.patch)?;
+            let dropped = series.remove(idx);
+            format!("Drop patch {} from {}", dropped.name, dep_path)
+        }
+        PatchCommand::Move(args) => {
+            let idx = find_patch(&patches, &args.patch)?;
+            if args.position == 0 || args.position > patches.len() {
+                bail!("Position must be between 1 and {}", patches.len());
+            }
+            if args.position == idx + 1 {
+                bail!(
+                    "Patch {} is already at position {}",
+                    patches[idx],
+                    args.position
+                );
+            }
+            let patch = series.remove(idx);
+            let message = format!(
+                "Move patch {} to position {} in {}",
+                patch.name, args.position, dep_path
+            );
+            series.insert(args.position - 1, patch);
+            message
+        }
+        PatchCommand::Rename(args) => {
+            let idx = find_patch(&patches, &args.patch)?;
+            let slug = slugify(&args.name).ok_or_else(|| {
+                anyhow!("Patch name must contain letters or digits: {:?}", args.name)
+            })?;
+            let patch = &mut series[idx];
+            patch.name = match patch.name.split_once('-') {
+                Some((number, _)) if number.parse::<u32>().is_ok() => {
+                    format!("{number}-{slug}.patch")
+                }
+                _ => format!("{slug}.patch"),
+            };
+            patch.header = replace_patch_subject(&patch.header, &args.name);
+            format!(
+                "Rename patch {} to {} in {}",
+                patches[idx], patch.name, dep_path
+            )
+        }
+        PatchCommand::Squash(args) => {
+            let idx = find_patch(&patches, &args.patch)?;
+            let other_idx = find_patch(&patches, &args.other)?;
+            if idx == other_idx {
+                bail!("Can't squash patch {} into itself", patches[idx]);
+            }
+            let other = series.remove(other_idx);
+            let patch = &mut series[if other_idx < idx { idx - 1 } else { idx }];
+            patch.sources.extend(other.sources);
+            if patch.header.is_empty() {
+                patch.header = other.header;
+            } else {
+                let folded = [
+                    patch_subject(&other.header),
+                    patch_description(&other.header),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join("\n\n");
+                if !folded.is_empty() {
+                    patch.header = append_patch_description(&patch.header, &folded);
+                }
+            }
+            format!(
+                "Squash patch {} into {} in {}",
+                other.name, patch.name, dep_path
+            )
+        }
+        PatchCommand::Edit(args) => {
+            return match args.patch {
+                Some(patch) => {
+                    let idx = find_patch(&patches, &patch)?;
+                    start_patch_edit(paths, &target_dir, dep_path.as_str(), &patches, idx)
+                }
+                None => finish_patch_edit(paths, &target_dir, dep_path.as_str()),
+            };
+        }
+    };
+
+    rewrite_patches(paths, &target_dir, series, &message)?;
+    println!("{message}");
+
+    Ok(())
+}
+
+fn list_patches(paths: &MonorepoPaths, target_dir: &Path, series: &[SeriesPatch]) -> Result<()> {
+    for (idx, patch) in series.iter().enumerate() {
+        println!(
+            "{:>3}  {}  {}",
+            idx + 1,
+            patch.name,
+            patch_subject(&patch.header).unwrap_or_default()
+        );
+    }
+    for entry in load_series(paths, target_dir)?.unwrap_or_default() {
+        if !entry.enabled {
+            println!("  -  {}  (disabled)", entry.name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the patch by its 1-based position or file name.
+fn find_patch(patches: &[String], patch: &str) -> Result<usize> {
+    if let Ok(position) = patch.parse::<usize>() {
+        if position == 0 || position > patches.len() {
+            bail!(
+                "No patch at position {}, the dependency has {} patches",
+                position,
+                patches.len()
+            );
+        }
+        return Ok(position - 1);
+    }
+
+    patches
+        .iter()
+        .position(|p| p == patch)
+        .ok_or_else(|| anyhow!("Patch {} not found, see `dockyard patch list`", patch))
+}
+
+/// Returns the title from the `Subject:` line of the patch header.
+fn patch_subject(header: &[u8]) -> Option<String> {
+    let header = String::from_utf8_lossy(header);
+    let subject = header.lines().find_map(|l| l.strip_prefix("Subject: "))?;
+    let subject = match subject.strip_prefix('[') {
+        Some(tagged) => tagged.split_once("] ").map_or(subject, |(_, title)| title),
+        None => subject,
+    };
+
+    Some(subject.to_string())
+}
+
+/// Replaces the `Subject:` line of the patch header, headers without one are
+/// kept as is.
+fn replace_patch_subject(header: &[u8], title: &str) -> Vec<u8> {
+    let header = String::from_utf8_lossy(header);
+    let mut new_header = String::new();
+    let mut in_subject = false;
+    for line in header.split_inclusive('\n') {
+        if line.starts_with("Subject: ") {
+            in_subject = true;
+            new_header.push_str(&format!("Subject: [PATCH] {}\n", title.trim()));
+            continue;
+        }
+        // Long subjects are folded onto indented lines
+        if in_subject && line.starts_with([' ', '\t']) {
+            continue;
+        }
+        in_subject = false;
+        new_header.push_str(line);
+    }
+
+    new_header.into_bytes()
+}
+
+/// Appends `text` as a paragraph to the description of the patch header, right
+/// above the `---` separator. Headers without the separator get it at the end.
+fn append_patch_description(header: &[u8], text: &str) -> Vec<u8> {
+    let header = String::from_utf8_lossy(header);
+    let new_header = match header.strip_suffix("---\n") {
+        Some(description) => format!("{}\n\n{}\n\n---\n", description.trim_end(), text.trim()),
+        None => format!("{}\n\n{}\n\n", header.trim_end(), text.trim()),
+    };
+
+    new_header.into_bytes()
+}
+
+/// Rebuilds the patches of the dependency from the recorded upstream commit
+/// and commits the result. Each patch in `series` is regenerated so that the
+/// series applies cleanly in the new order, patch files are renumbered unless
+/// the order comes from the series file. The repo dir is updated to the
+/// upstream tree with the new series applied.
+fn rewrite_patches(
+    paths: &MonorepoPaths,
+    target_dir: &Path,
+    mut series: Vec<SeriesPatch>,
+    message: &str,
+) -> Result<()> {
+    let mut metadata = load_metadata(paths, target_dir)?;
+    if metadata.update_state.is_some() {
+        bail!("Update is in progress");
+    }
+    ensure_git_clean(&paths.root)?;
+
+    let repo_dir = paths.repo_dir(target_dir);
+    let patches_dir = paths.patches_dir(target_dir);
+    let prev_patches = load_patch_list(paths, target_dir)?;
+    let series_file = load_series(paths, target_dir)?;
+    if series_file.is_none() {
+        for (idx, patch) in series.iter_mut().enumerate() {
+            let name = patch.name.split_once('-').map_or(&*patch.name, |(_, n)| n);
+            patch.name = format!("{:04}-{}", idx + 1, name);
+        }
+    }
+    for patch in series.iter() {
+        if patches_dir.join(&patch.name).exists() && !prev_patches.contains(&patch.name) {
+            bail!("Patch {} already exists", patch.name);
+        }
+    }
+
+    let (scratch_dir, scratch_repo) = scratch_upstream_repo(paths, target_dir, &metadata)?;
+    ensure_changes_recorded(&scratch_repo, &patches_dir, &prev_patches, &repo_dir)?;
+
+    let mut diffs = Vec::new();
+    for patch in series.iter() {
+        for source in patch.sources.iter() {
+            apply_patch_file(&scratch_repo, &patches_dir, source)
+                .context("The series doesn't apply cleanly to the recorded upstream anymore")?;
+        }
+        diffs.push(commit_scratch_patch(&scratch_repo, &patch.name)?);
+    }
+
+    // Write the new patches next to the scratch repo and swap them in last, so
+    // that the dependency is left untouched if anything fails before
+    let new_patches_dir = scratch_dir.path().join("patches");
+    fs::create_dir(&new_patches_dir)?;
+    for (patch, diff) in series.iter().zip(diffs) {
+        let mut file = File::create(new_patches_dir.join(&patch.name))?;
+        file.write_all(&patch.header)?;
+        file.write_all(&diff)?;
+    }
+    if series_file.is_some() {
+        let series_path = new_patches_dir.join(SERIES_FILE);
+        fs::copy(patches_dir.join(SERIES_FILE), &series_path)?;
+        rewrite_series(&series_path, &series)?;
+    }
+
+    for patch in prev_patches.iter() {
+        fs::remove_file(patches_dir.join(patch))?;
+    }
+    for entry in fs::read_dir(&new_patches_dir)? {
+        let entry = entry?;
+        fs::rename(entry.path(), patches_dir.join(entry.file_name()))?;
+    }
+    replace_repo_dir(&scratch_repo, &repo_dir)?;
+
+    metadata.patched_tree_hash = Some(hash_tree(&repo_dir)?);
+    update_metadata(paths, target_dir, &metadata)?;
+    drop(scratch_dir);
+    commit_code(message, &paths.root)
+}
+
+/// Creates a scratch git repo in the dependency dir with the recorded upstream
+/// tree committed. The repo is on the same filesystem as the repo dir to move
+/// the result in place.
+fn scratch_upstream_repo(
+    paths: &MonorepoPaths,
+    target_dir: &Path,
+    metadata: &DependencyMetadata,
+) -> Result<(tempfile::TempDir, PathBuf)> {
+    let scratch_dir = tempfile::Builder::new()
+        .prefix(".patch")
+        .tempdir_in(target_dir)?;
+    let scratch_repo = scratch_dir.path().join("repo");
+    fetch_recorded_upstream(paths, metadata, &scratch_repo)?;
+    git_output(&scratch_repo, &["init", "--quiet"])?;
+    commit_scratch(&scratch_repo, "Upstream")?;
+
+    Ok((scratch_dir, scratch_repo))
+}
+
+/// Makes sure the repo dir is the upstream tree with `patches` applied, so
+/// that rebuilding it from patches loses nothing. The scratch repo is reset
+/// back to upstream afterwards.
+fn ensure_changes_recorded(
+    scratch_repo: &Path,
+    patches_dir: &Path,
+    patches: &[String],
+    repo_dir: &Path,
+) -> Result<()> {
+    for patch in patches.iter() {
+        apply_patch_file(scratch_repo, patches_dir, patch)
+            .context("The patches don't apply to the recorded upstream")?;
+    }
+    if !diff_trees(scratch_repo, repo_dir)?.is_empty() {
+        bail!(
+            "Vendored code has changes not recorded in patches, extract them with \
+            `dockyard extract-patch` or check them with `dockyard verify`"
+        );
+    }
+    git_output(scratch_repo, &["reset", "--quiet", "--hard", "HEAD"])?;
+    git_output(scratch_repo, &["clean", "--quiet", "-fd"])?;
+
+    Ok(())
+}
+
+/// Commits changes in the scratch repo as the patch and returns its diff.
+fn commit_scratch_patch(scratch_repo: &Path, patch_name: &str) -> Result<Vec<u8>> {
+    commit_scratch(scratch_repo, patch_name)?;
+    let diff_cmd = Command::new("git")
+        .current_dir(scratch_repo)
+        .args(["diff", "--binary", "HEAD~1", "HEAD"])
+        .output()?;
+    if !diff_cmd.status.success() {
+        bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&diff_cmd.stderr)
+        );
+    }
+    if diff_cmd.stdout.is_empty() {
+        bail!(
+            "Patch {} would have no changes, drop it with `dockyard patch drop`",
+            patch_name
+        );
+    }
+
+    Ok(diff_cmd.stdout)
+}
+
+/// Moves the scratch repo tree in place of the repo dir.
+fn replace_repo_dir(scratch_repo: &Path, repo_dir: &Path) -> Result<()> {
+    fs::remove_dir_all(scratch_repo.join(".git"))?;
+    fs::remove_dir_all(repo_dir)?;
+    fs::rename(scratch_repo, repo_dir)?;
+
+    Ok(())
+}
+
+/// Starts editing the patch at `idx`: the repo dir is rebuilt as upstream
+/// with the patches up to the edited one applied. Later patches are rebased
+/// onto the edited one by `dockyard patch edit --finish`.
+fn start_patch_edit(
+    paths: &MonorepoPaths,
+    target_dir: &Path,
+    canonical_path: &str,
+    patches: &[String],
+    idx: usize,
+) -> Result<()> {
+    let mut metadata = load_metadata(paths, target_dir)?;
+    if metadata.update_state.is_some() {
+        bail!("Update is in progress");
+    }
+    ensure_git_clean(&paths.root)?;
+
+    let repo_dir = paths.repo_dir(target_dir);
+    let patches_dir = paths.patches_dir(target_dir);
+    let (scratch_dir, scratch_repo) = scratch_upstream_repo(paths, target_dir, &metadata)?;
+    ensure_changes_recorded(&scratch_repo, &patches_dir, patches, &repo_dir)?;
+    for patch in patches[..=idx].iter() {
+        apply_patch_file(&scratch_repo, &patches_dir, patch)?;
+    }
+    replace_repo_dir(&scratch_repo, &repo_dir)?;
+    drop(scratch_dir);
+
+    metadata.update_state = Some(UpdateState {
+        prev_commit_hash: get_current_commit(&paths.root)?,
+        new_revision: metadata.revision.clone(),
+        patches: patches
+            .iter()
+            .enumerate()
+            .map(|(patch_idx, name)| PatchApplyState {
+                name: name.clone(),
+                state: if patch_idx < idx {
+                    PatchState::Applied
+                } else {
+                    PatchState::Pending
+                },
+            })
+            .collect(),
+        branch: None,
+        original_branch: None,
+        squash: true,
+        upstream_shortlog: vec![],
+        no_commit: false,
+        snapshot_tree: None,
+        checkpoint_tree: None,
+        edited_patch: Some(patches[idx].clone()),
+    });
+    update_metadata(paths, target_dir, &metadata)?;
+    commit_code(
+        &format!("Start editing patch {} of {}", patches[idx], canonical_path),
+        &paths.root,
+    )?;
+
+    println!(
+        "{} now has patches up to {} applied. What to do next:
+
+1. Change the code in {}, add new files to git.
+2. Run the following command
+
+  dockyard patch edit --finish --path {}
+
+It'll regenerate the patch and rebase subsequent patches onto it. Run
+`dockyard update --abort {}` to drop the changes.
+",
+        repo_dir.strip_prefix(&paths.root)?.display(),
+        patches[idx],
+        repo_dir.strip_prefix(&paths.root)?.display(),
+        canonical_path,
+        canonical_path
+    );
+
+    Ok(())
+}
+
+/// Regenerates the edited patch from changes made since editing started,
+/// then rebases the subsequent patches onto it like `update --continue`.
+fn finish_patch_edit(paths: &MonorepoPaths, target_dir: &Path, canonical_path: &str) -> Result<()> {
+    let mut metadata = load_metadata(paths, target_dir)?;
+    let Some(mut update_state) = metadata.update_state.clone() else {
+        bail!("No patch is being edited");
+    };
+    let Some(edited_patch) = update_state.edited_patch.clone() else {
+        bail!("Update is in progress, continue it with `dockyard update --continue`");
+    };
+    let idx = update_state
+        .patches
+        .iter()
+        .position(|p| p.name == edited_patch)
+        .ok_or_else(|| anyhow!("Edited patch {} not found", edited_patch))?;
+
+    if update_state.patches[idx].state == PatchState::Pending {
+        let repo_dir = paths.repo_dir(target_dir);
+        let patches_dir = paths.patches_dir(target_dir);
+        let repo_path = repo_dir.to_string_lossy().to_string();
+        let status = git_output(&paths.root, &["status", "--porcelain", "--", &repo_path])?;
+        let changes = if status.is_empty() {
+            None
+        } else {
+            Some(extract_diff(&repo_dir, "HEAD", paths)?)
+        };
+
+        let (scratch_dir, scratch_repo) = scratch_upstream_repo(paths, target_dir, &metadata)?;
+        for patch in update_state.patches[..idx].iter() {
+            apply_patch_file(&scratch_repo, &patches_dir, &patch.name)?;
+        }
+        commit_scratch(&scratch_repo, "Previous patches")?;
+        apply_patch_file(&scratch_repo, &patches_dir, &edited_patch)?;
+        if let Some(changes) = changes {
+            fs::write(scratch_dir.path().join("changes.diff"), changes)?;
+            apply_patch_file(&scratch_repo, scratch_dir.path(), "changes.diff")?;
+        }
+        let diff = commit_scratch_patch(&scratch_repo, &edited_patch)?;
+
+        let patch_path = patches_dir.join(&edited_patch);
+        let prev_patch = fs::read(&patch_path)?;
+        let mut file = File::create(&patch_path)?;
+        file.write_all(patch_header(&prev_patch))?;
+        file.write_all(&diff)?;
+        drop(scratch_dir);
+        println!("Patch {} updated", patch_path.display());
+
+        update_state.patches[idx].state = PatchState::Resolved;
+        metadata.update_state = Some(update_state);
+        update_metadata(paths, target_dir, &metadata)?;
+        commit_code(
+            &format!("Update patch {} of {}", edited_patch, canonical_path),
+            &paths.root,
+        )?;
+    }
+
+    apply_patches(
+        target_dir,
+        canonical_path,
+        paths,
+        &paths.config.templates,
+        &mut metadata,
+    )?;
+    finish_update(target_dir, canonical_path, paths, metadata)
+}
+
+/// Commits everything in the scratch repo, including files ignored by the
+/// upstream `.gitignore`.
+fn commit_scratch(repo_dir: &Path, message: &str) -> Result<()> {
+    git_output(repo_dir, &["add", "--all", "--force"])?;
+    git_output(
+        repo_dir,
+        &[
+            "-c",
+            "user.name=dockyard",
+            "-c",
+            "user.email=dockyard@localhost",
+            "-c",
+            "commit.gpgsign=false",
+            "commit",
+            "--quiet",
+            "--no-verify",
+            "--allow-empty",
+            "-m",
+            message,
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Writes enabled entries of the series file in the new order. Comments,
+/// disabled entries and trailing comments of the kept patches stay in place.
+fn rewrite_series(series_path: &Path, series: &[SeriesPatch]) -> Result<()> {
+    let content = fs::read_to_string(series_path)?;
+    let is_entry = |line: &str| !split_series_comment(line).0.is_empty();
+
+    let mut trailing_comments = std::collections::HashMap::new();
+    for line in content.lines() {
+        let (name, comment) = split_series_comment(line);
+        if !name.is_empty() {
+            trailing_comments.insert(name, comment);
+        }
+    }
+
+    let mut new_content = String::new();
+    let mut patches = series.iter();
+    for line in content.lines() {
+        if !is_entry(line) {
+            new_content.push_str(line);
+            new_content.push('\n');
+        } else if let Some(patch) = patches.next() {
+            new_content.push_str(&patch.name);
+            let comment = patch
+                .sources
+                .first()
+                .and_then(|source| trailing_comments.get(&**source))
+                .or_else(|| trailing_comments.get(&*patch.name));
+            new_content.push_str(comment.unwrap_or(&""));
+            new_content.push('\n');
+        }
+    }
+    for patch in patches {
+        new_content.push_str(&patch.name);
+        new_content.push('\n');
+    }
+    fs::write(series_path, new_content)?;
+
+    Ok(())
+}
+
+/// Branch of the workspace with a commit per patch.
+const WORKSPACE_BRANCH: &str = "dockyard";
+/// Commit message trailer with the patch the workspace commit was made from.
+const PATCH_TRAILER: &str = "Dockyard-Patch";
+
+pub fn workspace(args: WorkspaceCommandArgs, paths: &paths::MonorepoPaths) -> Result<()> {
+    match args.command {
+        WorkspaceCommand::Open(args) => open_workspace(args, paths),
+        WorkspaceCommand::Sync(args) => sync_workspace(args, paths),
+    }
+}
+
+/// Returns the monorepo root recorded in the workspace `dir` or the current
+/// dir, `None` if it isn't a workspace.
+pub fn workspace_root(dir: Option<&Path>) -> Result<Option<PathBuf>> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => std::env::current_dir()?,
+    };
+    let config_cmd = Command::new("git")
+        .current_dir(&dir)
+        .args(["config", "--get", "dockyard.root"])
+        .stderr(Stdio::null())
+        .output();
+    match config_cmd {
+        Ok(output) if output.status.success() => Ok(Some(PathBuf::from(
+            String::from_utf8(output.stdout)?.trim(),
+        ))),
+        _ => Ok(None),
+    }
+}
+
+fn open_workspace(args: WorkspaceOpenArgs, paths: &MonorepoPaths) -> Result<()> {
+    let dep_path = paths.resolve_dependency(args.path.as_ref())?;
+    let target_dir = dep_path.to_abs(paths)?;
+    let metadata = load_metadata(paths, &target_dir)?;
+    if metadata.update_state.is_some() {
+        bail!("Update is in progress");
+    }
+    let patches = load_patch_list(paths, &target_dir)?;
+
+    let (temp_dir, workspace_dir) = match args.dir {
+        Some(dir) => {
+            let dir = std::env::current_dir()?.join(dir);
+            if dir.exists() && fs::read_dir(&dir)?.next().is_some() {
+                bail!("{} is not empty", dir.display());
+            }
+            fs::create_dir_all(&dir)?;
+            (None, dir)
+        }
+        None => {
+            let name = dep_path.name().unwrap_or_default();
+            let temp_dir = tempfile::Builder::new()
+                .prefix(&format!("dockyard-{name}-"))
+                .tempdir()?;
+            let dir = temp_dir.path().to_path_buf();
+            (Some(temp_dir), dir)
+        }
+    };
+
+    let base = init_workspace(paths, &metadata, &workspace_dir)?;
+    let patches_dir = paths.patches_dir(&target_dir);
+    for patch in patches.iter() {
+        let patch_path = patches_dir.join(patch);
+        let mut apply_cmd = Command::new("git");
+        apply_cmd
+            .current_dir(&workspace_dir)
+            .args(["apply", "--index", "--whitespace=nowarn"]);
+        if let Some(ref subdir) = metadata.subdir {
+            apply_cmd.arg(format!("--directory={subdir}"));
+        }
+        apply_cmd.arg(&patch_path);
+        run_command(apply_cmd, "git apply", None)
+            .with_context(|| format!("Failed to apply patch {patch}"))?;
+
+        let header = patch_header(&fs::read(&patch_path)?).to_vec();
+        let mut commit_cmd = Command::new("git");
+        commit_cmd.current_dir(&workspace_dir).args([
+            "commit",
+            "--quiet",
+            "--no-verify",
+            "-m",
+            &workspace_commit_message(patch, &header),
+        ]);
+        if let Some(author) = patch_header_field(&header, "From") {
+            commit_cmd.arg(format!("--author={author}"));
+        }
+        if let Some(date) = patch_header_field(&header, "Date") {
+            commit_cmd.arg(format!("--date={date}"));
+        }
+        run_command(commit_cmd, "git commit", None)
+            .with_context(|| format!("Failed to commit patch {patch}"))?;
+    }
+
+    let root = paths.root.to_string_lossy();
+    for (key, value) in [
+        ("dockyard.root", &*root),
+        ("dockyard.path", dep_path.as_str()),
+        ("dockyard.base", &base),
+        ("dockyard.upstream", &metadata.revision.resolved_commit),
+    ] {
+        git_output(&workspace_dir, &["config", key, value])?;
+    }
+    if let Some(temp_dir) = temp_dir {
+        let _ = temp_dir.keep();
+    }
+
+    println!(
+        "Opened workspace for {} in {}
+
+Branch {} has a commit per patch on top of the vendored tree {}.
+Rework the commits, e.g. with `git rebase -i`, then run
+
+  dockyard workspace sync
+
+in the workspace to turn them back into patches.
+",
+        dep_path,
+        workspace_dir.display(),
+        WORKSPACE_BRANCH,
+        base
+    );
+
+    Ok(())
+}
+
+/// Creates the workspace repo with the vendored upstream tree checked out on
+/// the workspace branch and returns its commit. Git upstreams are cloned with
+/// the full history, vendored files are committed on top of the recorded
+/// commit if they differ from the upstream tree.
+fn init_workspace(
+    paths: &MonorepoPaths,
+    metadata: &DependencyMetadata,
+    workspace_dir: &Path,
+) -> Result<String> {
+    if metadata.archive.is_some() {
+        git_output(workspace_dir, &["init", "--quiet"])?;
+        git_output(
+            workspace_dir,
+            &[
+                "symbolic-ref",
+                "HEAD",
+                &format!("refs/heads/{WORKSPACE_BRANCH}"),
+            ],
+        )?;
+    } else {
+        paths.config.upstream.check_url(&metadata.url)?;
+        let mut clone_cmd = Command::new("git");
+        clone_cmd
+            .args(["clone", "--quiet", &metadata.url])
+            .arg(workspace_dir);
+        run_command(clone_cmd, "clone", None).context("Failed to clone repo")?;
+        git_output(
+            workspace_dir,
+            &[
+                "checkout",
+                "--quiet",
+                "-b",
+                WORKSPACE_BRANCH,
+                &metadata.revision.resolved_commit,
+            ],
+        )?;
+    }
+
+    let vendored_dir = tempfile::Builder::new()
+        .prefix(".vendored")
+        .tempdir_in(workspace_dir)?;
+    let vendored_repo = vendored_dir.path().join("repo");
+    fetch_recorded_upstream(paths, metadata, &vendored_repo)?;
+
+    let prefix = metadata.subdir.as_deref().unwrap_or(".");
+    git_output(
+        workspace_dir,
+        &["rm", "-r", "--quiet", "--ignore-unmatch", "--", prefix],
+    )?;
+    let prefix_dir = workspace_dir.join(prefix);
+    fs::create_dir_all(&prefix_dir)?;
+    for entry in fs::read_dir(&vendored_repo)? {
+        let entry = entry?;
+        fs::rename(entry.path(), prefix_dir.join(entry.file_name()))?;
+    }
+    drop(vendored_dir);
+
+    git_output(workspace_dir, &["add", "--all", "--force", "--", prefix])?;
+    if !git_output(workspace_dir, &["status", "--porcelain"])?.is_empty() {
+        git_output(
+            workspace_dir,
+            &[
+                "commit",
+                "--quiet",
+                "--no-verify",
+                "-m",
+                &format!("Vendored tree of {}", metadata.revision),
+            ],
+        )?;
+    }
+
+    get_current_commit(workspace_dir)
+}
+
+/// Message of the workspace commit made from the patch: the patch title and
+/// description followed by the patch trailer.
+fn workspace_commit_message(patch: &str, header: &[u8]) -> String {
+    let title = patch_subject(header)
+        .unwrap_or_else(|| patch.strip_suffix(".patch").unwrap_or(patch).to_string());
+    let mut message = format!("{title}\n\n");
+    if let Some(description) = patch_description(header) {
+        message.push_str(&description);
+        message.push_str("\n\n");
+    }
+    message.push_str(&format!("{PATCH_TRAILER}: {patch}\n"));
+
+    message
+}
+
+/// Returns the value of the `field` line of the patch header, e.g. `Date`.
+fn patch_header_field(header: &[u8], field: &str) -> Option<String> {
+    let header = String::from_utf8_lossy(header);
+    let prefix = format!("{field}: ");
+    let value = header.lines().find_map(|l| l.strip_prefix(&prefix))?;
+
+    Some(value.to_string())
+}
+
+/// Returns the text between the `Subject:` line and the `---` separator of
+/// the patch header.
+fn patch_description(header: &[u8]) -> Option<String> {
+    let header = String::from_utf8_lossy(header);
+    let description = header
+        .lines()
+        .skip_while(|l| !l.starts_with("Subject: "))
+        .skip(1)
+        // Long subjects are folded onto indented lines
+        .skip_while(|l| l.starts_with([' ', '\t']))
+        .take_while(|l| *l != "---")
+        .collect::<Vec<_>>()
+        .join("\n");
+    let description = description.trim();
+
+    (!description.is_empty()).then(|| description.to_string())
+}
+
+fn sync_workspace(args: WorkspaceSyncArgs, paths: &MonorepoPaths) -> Result<()> {
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => std::env::current_dir()?,
+    };
+    let workspace_dir = PathBuf::from(git_output(&dir, &["rev-parse", "--show-toplevel"])?.trim());
+    let workspace_config = |key: &str| {
+        git_output(&workspace_dir, &["config", "--get", key])
+            .map(|value| value.trim().to_string())
+            .with_context(|| format!("{} is not a dockyard workspace", workspace_dir.display()))
+    };
+    let dep_path: CanonicalPath = workspace_config("dockyard.path")?.parse()?;
+    let base = workspace_config("dockyard.base")?;
+    let upstream = workspace_config("dockyard.upstream")?;
+
+    let target_dir = dep_path.to_abs(paths)?;
+    let metadata = load_metadata(paths, &target_dir)?;
+    if metadata.revision.resolved_commit != upstream {
+        bail!(
+            "{} was updated since the workspace was opened, open a new one",
+            dep_path
+        );
+    }
+    if !git_output(
+        &workspace_dir,
+        &["status", "--porcelain", "--untracked-files=no"],
+    )?
+    .is_empty()
+    {
+        bail!("Workspace has uncommitted changes");
+    }
+    let is_ancestor = Command::new("git")
+        .current_dir(&workspace_dir)
+        .args(["merge-base", "--is-ancestor", &base, "HEAD"])
+        .status()?
+        .success();
+    if !is_ancestor {
+        bail!("Workspace HEAD isn't based on the vendored tree {base}, rebase onto it");
+    }
+    let range = format!("{base}..HEAD");
+    if !git_output(&workspace_dir, &["rev-list", "--merges", &range])?.is_empty() {
+        bail!("Merge commits can't be turned into patches, rebase them");
+    }
+    let commits = git_output(&workspace_dir, &["rev-list", "--reverse", &range])?;
+
+    let prev_patches = load_patch_list(paths, &target_dir)?;
+    let has_series = load_series(paths, &target_dir)?.is_some();
+    let patches_dir = paths.patches_dir(&target_dir);
+    let export_dir = tempfile::tempdir()?;
+    let mut series = Vec::new();
+    for (idx, commit) in commits.lines().enumerate() {
+        let message = git_output(&workspace_dir, &["log", "-1", "--format=%B", commit])?;
+        let trailer = git_output(
+            &workspace_dir,
+            &[
+                "log",
+                "-1",
+                &format!("--format=%(trailers:key={PATCH_TRAILER},valueonly)"),
+                commit,
+            ],
+        )?;
+        let trailer = trailer.lines().next().unwrap_or_default().trim();
+        let diff = workspace_commit_diff(&workspace_dir, commit, metadata.subdir.as_deref())?;
+
+        // Patches of unchanged commits are kept as is
+        if prev_patches.iter().any(|p| p == trailer) {
+            let content = fs::read(patches_dir.join(trailer))?;
+            let header = patch_header(&content);
+            if message.trim() == workspace_commit_message(trailer, header).trim()
+                && patch_id(&workspace_dir, &content)? == patch_id(&workspace_dir, &diff)?
+            {
+                series.push(SeriesPatch {
+                    name: trailer.to_string(),
+                    sources: vec![trailer.to_string()],
+                    header: header.to_vec(),
+                });
+                continue;
+            }
+        }
+
+        let title = message.lines().next().unwrap_or_default();
+        let trailer_prefix = format!("{PATCH_TRAILER}:");
+        let description = message
+            .lines()
+            .skip(1)
+            .filter(|l| !l.starts_with(&trailer_prefix))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let name = if !trailer.is_empty() {
+            trailer.to_string()
+        } else {
+            let slug = slugify(title)
+                .ok_or_else(|| anyhow!("Can't make patch name from subject of commit {commit}"))?;
+            if has_series {
+                format!("{slug}.patch")
+            } else {
+                format!("{:04}-{slug}.patch", idx + 1)
+            }
+        };
+        check_patch_name(&name)?;
+        if has_series && series.iter().any(|p: &SeriesPatch| p.name == name) {
+            bail!("Several commits would be written to patch {name}, reword one of them");
+        }
+
+        let source = export_dir.path().join(format!("{idx}.patch"));
+        fs::write(&source, &diff)?;
+        let ident = git_output(
+            &workspace_dir,
+            &["log", "-1", "--date=raw", "--format=%an <%ae> %ad", commit],
+        )?;
+        let header = format_patch_header_for(&ident, title, Some(&description))?;
+        series.push(SeriesPatch {
+            name,
+            sources: vec![source.to_string_lossy().to_string()],
+            header: header.into_bytes(),
+        });
+    }
+
+    let unchanged = series.len() == prev_patches.len()
+        && series
+            .iter()
+            .zip(prev_patches.iter())
+            .all(|(patch, prev)| patch.sources == [prev.clone()]);
+    if unchanged {
+        println!("Patches of {dep_path} are up to date");
+        return Ok(());
+    }
+
+    let message = format!("Sync patches of {dep_path} from workspace");
+    rewrite_patches(paths, &target_dir, series, &message)?;
+    println!("{message}");
+
+    Ok(())
+}
+
+/// Diff of the workspace commit relative to the vendored subdir.
+fn workspace_commit_diff(
+    workspace_dir: &Path,
+    commit: &str,
+    subdir: Option<&str>,
+) -> Result<Vec<u8>> {
+    let parent = format!("{commit}~1");
+    if let Some(subdir) = subdir {
+        let outside = git_output(
+            workspace_dir,
+            &[
+                "diff",
+                "--name-only",
+                &parent,
+                commit,
+                "--",
+                ".",
+                &format!(":(exclude){subdir}"),
+            ],
+        )?;
+        if !outside.is_empty() {
+            bail!("Commit {commit} changes files outside of the vendored subdir {subdir}");
+        }
+    }
+
+    let mut diff_cmd = Command::new("git");
+    diff_cmd
+        .current_dir(workspace_dir)
+        .args(["diff", "--binary"]);
+    if let Some(subdir) = subdir {
+        diff_cmd.arg(format!("--relative={subdir}"));
+    }
+    let diff_cmd = diff_cmd.args([&parent, commit]).output()?;
+    if !diff_cmd.status.success() {
+        bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&diff_cmd.stderr)
+        );
+    }
+
+    Ok(diff_cmd.stdout)
+}
+
+/// Returns the id of the changes in the patch, it doesn't depend on the
+/// header and line numbers of hunks.
+fn patch_id(current_dir: &Path, patch: &[u8]) -> Result<String> {
+    let mut child = Command::new("git")
+        .current_dir(current_dir)
+        .args(["patch-id", "--verbatim"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(patch)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("git patch-id failed");
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+
+    Ok(stdout
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Returns the dependency dir for `path` or all dependency dirs if `path`
+/// isn't provided.
+fn dependency_dirs(
+    paths: &paths::MonorepoPaths,
+    path: Option<&DependencyArg>,
+) -> Result<Vec<PathBuf>> {
+    if let Some(path) = path {
+        let target_dir = paths.resolve_dependency(Some(path))?.to_abs(paths)?;
+        Ok(vec![target_dir])
+    } else {
+        Ok(paths.find_dependencies()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, process::Command};
+
+    use anyhow::{Context, bail};
+    use dockyard::{paths::path_to_abs, *};
+    use tempfile::{TempDir, tempdir};
+
+    use super::*;
+    use crate::{
+        PatchDropArgs, PatchEditArgs, PatchMoveArgs, PatchRenameArgs, PatchSquashArgs,
+        WorkspaceCommand, WorkspaceCommandArgs, WorkspaceOpenArgs, WorkspaceSyncArgs,
+    };
+    use dockyard::config::CONFIG_FILE;
+
+    #[test]
+    fn test_extract_patch() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+
+        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
+            .context("Could not find monorepo checkout paths")?;
+        let target_dir = path_to_abs(&paths, "//third_party/repo_extract")?;
+        fs::create_dir_all(&target_dir)?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        fs::write(target_dir.join("tesfile.txt"), "line1\nline2\n")?;
+
+        git_add_all(&paths.root)?;
+        let diff = extract_diff(&target_dir, "HEAD", &paths)?;
+
+        let diff_str = String::from_utf8_lossy(&diff);
+
+        let expected_diff = "diff --git a/tesfile.txt b/tesfile.txt
+new file mode 100644
+index 0000000..c0d0fb4
+--- /dev/null
++++ b/tesfile.txt
+@@ -0,0 +1,2 @@
++line1
++line2
+";
+        assert_eq!(diff_str, expected_diff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_patch_with_repo_subdir() -> anyhow::Result<()> {
+        // Git expects forward slashes ('/') as the path separator. So let's check that the
+        // functions works properly with possible backslashes after PahtBuf::join as well.
+        let temp_dir = create_test_dir()?;
+
+        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
+            .context("Could not find monorepo checkout paths")?;
+        let target_dir = path_to_abs(&paths, "//third_party/repo_extract_backslash")?;
+        let repo_dir = target_dir.join("repo");
+        fs::create_dir_all(&repo_dir)?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        fs::write(repo_dir.join("tesfile.txt"), "line1\nline2\n")?;
+
+        git_add_all(&paths.root)?;
+        let diff = extract_diff(&repo_dir, "HEAD", &paths)?;
+
+        let diff_str = String::from_utf8_lossy(&diff);
+
+        let expected_diff = "diff --git a/tesfile.txt b/tesfile.txt
+new file mode 100644
+index 0000000..c0d0fb4
+--- /dev/null
++++ b/tesfile.txt
+@@ -0,0 +1,2 @@
++line1
++line2
+";
+        assert_eq!(diff_str, expected_diff);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_patch_error_on_untracked() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+
+        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
+            .context("Could not find monorepo checkout paths")?;
+        let target_dir = path_to_abs(&paths, "//third_party/repo_untracked")?;
+        fs::create_dir_all(&target_dir)?;
+
+        fs::write(target_dir.join("tesfile.txt"), "line1\nline2\n")?;
+
+        let res = extract_diff(&target_dir, "HEAD", &paths);
+        assert!(res.is_err(), "Expected Err, but get {:?}", res);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_patches() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line1
++line123
+ line2
+ line3
+",
+        )?;
+        commit_code("Create patch1", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        apply_patches(
+            &target_dir,
+            canonical_path,
+            &paths,
+            &TemplatesConfig::default(),
+            &mut metadata,
+        )?;
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            new_metadata.update_state.clone().unwrap().patches[0].state,
+            PatchState::Applied,
+            "expected Applied state for the patch, got {:?}",
+            new_metadata.update_state.unwrap()
+        );
+
+        let content = fs::read_to_string(target_dir.join("repo/a.txt"))?;
+        assert_eq!(
+            content,
+            "line123
+line2
+line3
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_multiple_patches() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line1
++line123
+ line2
+ line3
+",
+        )?;
+        commit_code("Create patch1", &target_dir)?;
+
+        fs::write(
+            target_dir.join("patches/0002-update-line4.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line123
+ line2
+ line3
++line4
+",
+        )?;
+        commit_code("Create patch2", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        apply_patches(
+            &target_dir,
+            canonical_path,
+            &paths,
+            &TemplatesConfig::default(),
+            &mut metadata,
+        )?;
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            new_metadata.update_state.clone().unwrap().patches[0].state,
+            PatchState::Applied,
+            "expected Applied state for the patch, got {:?}",
+            new_metadata.update_state.unwrap()
+        );
+        assert_eq!(
+            new_metadata.update_state.clone().unwrap().patches[1].state,
+            PatchState::Applied,
+            "expected Applied state for the patch, got {:?}",
+            new_metadata.update_state.unwrap()
+        );
+
+        let content = fs::read_to_string(target_dir.join("repo/a.txt"))?;
+        assert_eq!(
+            content,
+            "line2
+line3
+line4
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_patch_with_conflict() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line999
++line123
+ line2
+ line3
+",
+        )?;
+        commit_code("Create patch1", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        let apply_result = update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(
+            apply_result.is_err(),
+            "Expected Err, but got {:?}",
+            apply_result
+        );
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            new_metadata.update_state.clone().unwrap().patches[0].state,
+            PatchState::Conflict,
+            "expected Conflict state for the patch, got {:?}",
+            new_metadata.update_state.unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_patch_with_conflict_and_continue() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line999
++line123
+ line2
+ line3
+",
+        )?;
+        commit_code("Create patch1", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        let apply_result = update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(
+            apply_result.is_err(),
+            "Expected Err, but got {:?}",
+            apply_result
+        );
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            new_metadata.update_state.clone().unwrap().patches[0].state,
+            PatchState::Conflict,
+            "expected Conflict state for the patch, got {:?}",
+            new_metadata.update_state.unwrap()
+        );
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line333
+line2
+line3
+",
+        )?;
+
+        update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert!(
+            new_metadata.update_state.is_none(),
+            "Expected None, but got {:?}",
+            new_metadata.update_state
+        );
+        assert_eq!(new_metadata.revision.resolved_commit, "12345".to_string());
+
+        let patch_content = fs::read_to_string(target_dir.join("patches/0001-update-line1.patch"))?;
+        assert_eq!(
+            normalize_patch(&patch_content),
+            "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line1
++line333
+ line2
+ line3",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_patch_with_name_and_message() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        let paths = MonorepoPaths::from_dir(root)?;
+        fs::write(root.join(".keep"), "")?;
+        commit_code("Initial commit", root)?;
+
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_dir.path().to_string_lossy().to_string()),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                name: Some("Fix build with musl!".to_string()),
+                message: Some("The build fails on Alpine.\n\nSee upstream issue.\n".to_string()),
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+        let patch_path = target_dir.join("patches/0001-fix-build-with-musl.patch");
+        let patch = fs::read_to_string(&patch_path)?;
+        let (header, diff) = patch.split_once("---\ndiff --git").unwrap();
+        let header_lines: Vec<_> = header.lines().collect();
+        assert_eq!(
+            header_lines[0],
+            "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001"
+        );
+        // Name <email> 1700000000 +0100
+        let ident = git_output(root, &["var", "GIT_AUTHOR_IDENT"])?;
+        let author = ident.trim().rsplitn(3, ' ').nth(2).unwrap();
+        assert_eq!(header_lines[1], format!("From: {author}"));
+        assert!(header_lines[2].starts_with("Date: "), "{header}");
+        assert_eq!(
+            header_lines[3..].join("\n"),
+            "Subject: [PATCH] Fix build with musl!
+
+The build fails on Alpine.
+
+See upstream issue.
+"
+        );
+        assert!(diff.contains("+v1 patched"), "{diff}");
+
+        fs::write(target_dir.join("repo/b.txt"), "b\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                message: Some("Add b.txt\n\nNeeded by the build.".to_string()),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        let patch = fs::read_to_string(target_dir.join("patches/0002-add-b-txt.patch"))?;
+        assert!(
+            patch.contains("Subject: [PATCH] Add b.txt\n\nNeeded by the build.\n\n---\n"),
+            "{patch}"
+        );
+        commit_code("Patch upstream", root)?;
+
+        let res = extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                name: Some("!!!".to_string()),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        // The header survives the refresh of a conflicted patch
+        let res = update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        fs::write(target_dir.join("repo/a.txt"), "v2 patched\n")?;
+        update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        let refreshed_patch = fs::read_to_string(&patch_path)?;
+        let (refreshed_header, refreshed_diff) =
+            refreshed_patch.split_once("---\ndiff --git").unwrap();
+        assert_eq!(refreshed_header, header);
+        assert!(refreshed_diff.contains("+v2 patched"), "{refreshed_diff}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_series() -> anyhow::Result<()> {
+        let (_upstream_dir, temp_dir, paths) =
+            vendor_with_patches(&[("b.txt", "b.txt\n", "Add b"), ("c.txt", "c.txt\n", "Add c")])?;
+        let root = temp_dir.path();
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let patches_dir = target_dir.join("patches");
+        fs::write(
+            patches_dir.join("0003-add-d.patch"),
+            "diff --git a/d.txt b/d.txt
+new file mode 100644
+--- /dev/null
++++ b/d.txt
+@@ -0,0 +1 @@
++d.txt
+",
+        )?;
+        fs::write(patches_dir.join("README.md"), "Patches of upstream\n")?;
+
+        // Numeric order without series file, other files are ignored
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-b.patch", "0002-add-c.patch", "0003-add-d.patch"]
+        );
+        fs::rename(
+            patches_dir.join("0003-add-d.patch"),
+            patches_dir.join("0003-add-d.diff"),
+        )?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-b.patch", "0002-add-c.patch", "0003-add-d.diff"]
+        );
+        fs::rename(
+            patches_dir.join("0003-add-d.diff"),
+            patches_dir.join("0003-add-d.patch"),
+        )?;
+
+        fs::write(
+            patches_dir.join(SERIES_FILE),
+            "# Local changes
+0002-add-c.patch  # needed by b
+
+0001-add-b.patch
+#0003-add-d.patch",
+        )?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0002-add-c.patch", "0001-add-b.patch"]
+        );
+
+        fs::write(target_dir.join("repo/e.txt"), "e.txt\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                name: Some("Add e".to_string()),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0002-add-c.patch", "0001-add-b.patch", "0004-add-e.patch"]
+        );
+        commit_code("Patch upstream", root)?;
+
+        update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.requested_ref.as_deref(), Some("v2"));
+        for file in ["b.txt", "c.txt", "e.txt"] {
+            assert!(target_dir.join("repo").join(file).is_file(), "{file}");
+        }
+        assert!(!target_dir.join("repo/d.txt").exists());
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        assert_eq!(
+            fs::read_to_string(patches_dir.join(SERIES_FILE))?,
+            "# Local changes
+0002-add-c.patch  # needed by b
+
+0001-add-b.patch
+#0003-add-d.patch
+0004-add-e.patch
+"
+        );
+
+        // Patches missing from the series file or from the patches dir
+        fs::write(patches_dir.join("0005-add-f.patch"), "")?;
+        let err = format!("{:#}", load_patch_list(&paths, &target_dir).unwrap_err());
+        assert!(err.contains("0005-add-f.patch isn't listed"), "{err}");
+        fs::remove_file(patches_dir.join("0005-add-f.patch"))?;
+        fs::remove_file(patches_dir.join("0001-add-b.patch"))?;
+        let err = format!("{:#}", load_patch_list(&paths, &target_dir).unwrap_err());
+        assert!(err.contains("0001-add-b.patch listed in"), "{err}");
+
+        // Without the series file patch names must start with a number
+        fs::remove_file(patches_dir.join(SERIES_FILE))?;
+        fs::write(patches_dir.join("fix.diff"), "")?;
+        let err = format!("{:#}", load_patch_list(&paths, &target_dir).unwrap_err());
+        assert!(err.contains("fix.diff must start with a number"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_commands() -> anyhow::Result<()> {
+        let (_upstream_dir, temp_dir, paths) = vendor_with_patches(&[
+            ("b.txt", "b\n", "Add b"),
+            ("b.txt", "b\nb2\n", "Edit b\n\nAdd b2 line"),
+            ("c.txt", "c\n", "Add c"),
+        ])?;
+        let root = temp_dir.path();
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let patches_dir = target_dir.join("patches");
+
+        let run = |command| {
+            patch(
+                PatchCommandArgs {
+                    path: Some(canonical_path.parse()?),
+                    command,
+                },
+                &paths,
+            )
+        };
+        let last_commit_message = || git_output(root, &["log", "-1", "--format=%s"]);
+
+        run(PatchCommand::List)?;
+
+        run(PatchCommand::Move(PatchMoveArgs {
+            patch: "3".to_string(),
+            position: 1,
+        }))?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-c.patch", "0002-add-b.patch", "0003-edit-b.patch"]
+        );
+        assert_eq!(
+            last_commit_message()?,
+            "Move patch 0003-add-c.patch to position 1 in //third_party/upstream\n"
+        );
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+
+        // Edit b depends on Add b, nothing changes
+        let head = get_current_commit(root)?;
+        let res = run(PatchCommand::Move(PatchMoveArgs {
+            patch: "0003-edit-b.patch".to_string(),
+            position: 2,
+        }));
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_eq!(get_current_commit(root)?, head);
+        ensure_git_clean(root)?;
+
+        for command in [
+            PatchCommand::Drop(PatchDropArgs {
+                patch: "4".to_string(),
+            }),
+            PatchCommand::Drop(PatchDropArgs {
+                patch: "0004-missing.patch".to_string(),
+            }),
+            PatchCommand::Squash(PatchSquashArgs {
+                patch: "2".to_string(),
+                other: "2".to_string(),
+            }),
+            PatchCommand::Rename(PatchRenameArgs {
+                patch: "1".to_string(),
+                name: "!!!".to_string(),
+            }),
+        ] {
+            let res = run(command);
+            assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        }
+
+        run(PatchCommand::Squash(PatchSquashArgs {
+            patch: "2".to_string(),
+            other: "3".to_string(),
+        }))?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-c.patch", "0002-add-b.patch"]
+        );
+        let squashed = fs::read_to_string(patches_dir.join("0002-add-b.patch"))?;
+        assert!(
+            squashed.contains("Subject: [PATCH] Add b\n\nEdit b\n\nAdd b2 line\n\n---\n"),
+            "{squashed}"
+        );
+        assert!(squashed.contains("+b\n+b2\n"), "{squashed}");
+
+        run(PatchCommand::Rename(PatchRenameArgs {
+            patch: "1".to_string(),
+            name: "Add c file".to_string(),
+        }))?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-c-file.patch", "0002-add-b.patch"]
+        );
+        let renamed = fs::read_to_string(patches_dir.join("0001-add-c-file.patch"))?;
+        assert!(
+            renamed.contains("Subject: [PATCH] Add c file\n"),
+            "{renamed}"
+        );
+
+        run(PatchCommand::Drop(PatchDropArgs {
+            patch: "1".to_string(),
+        }))?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0001-add-b.patch"]
+        );
+        assert!(!target_dir.join("repo/c.txt").exists());
+        assert_eq!(
+            last_commit_message()?,
+            "Drop patch 0001-add-c-file.patch from //third_party/upstream\n"
+        );
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        ensure_git_clean(root)?;
+
+        // Changes not recorded in patches aren't lost
+        fs::write(target_dir.join("repo/a.txt"), "local change\n")?;
+        commit_code("Local change", root)?;
+        let res = run(PatchCommand::Drop(PatchDropArgs {
+            patch: "1".to_string(),
+        }));
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/a.txt"))?,
+            "local change\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_edit() -> anyhow::Result<()> {
+        let (_upstream_dir, temp_dir, paths) = vendor_with_patches(&[
+            ("b.txt", "b\n", "Add b"),
+            ("b.txt", "b\nb2\n", "Edit b"),
+            ("c.txt", "c\n", "Add c"),
+        ])?;
+        let root = temp_dir.path();
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let repo_dir = target_dir.join("repo");
+        let patches_dir = target_dir.join("patches");
+
+        let edit = |patch_arg: Option<&str>| {
+            patch(
+                PatchCommandArgs {
+                    path: Some(canonical_path.parse()?),
+                    command: PatchCommand::Edit(PatchEditArgs {
+                        patch: patch_arg.map(|p| p.to_string()),
+                        finish: patch_arg.is_none(),
+                    }),
+                },
+                &paths,
+            )
+        };
+        let cont = || {
+            update(
+                UpdateCommandArgs {
+                    cont: true,
+                    path: Some(canonical_path.parse()?),
+                    ..Default::default()
+                },
+                &paths,
+            )
+        };
+
+        // Edit without conflicts, subsequent patches are rebased
+        let head = get_current_commit(root)?;
+        edit(Some("1"))?;
+        assert_eq!(fs::read_to_string(repo_dir.join("b.txt"))?, "b\n");
+        assert!(!repo_dir.join("c.txt").exists());
+        let res = cont();
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        let res = edit(Some("2"));
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        fs::write(repo_dir.join("d.txt"), "d\n")?;
+        fs::write(repo_dir.join("d.bin"), b"\0\x01\x02\xff\0")?;
+        run_git(root, &["add", "."])?;
+        edit(None)?;
+        let edited = fs::read_to_string(patches_dir.join("0001-add-b.patch"))?;
+        assert!(edited.contains("Subject: [PATCH] Add b\n"), "{edited}");
+        assert!(edited.contains("+d\n"), "{edited}");
+        assert!(edited.contains("GIT binary patch"), "{edited}");
+        assert_eq!(fs::read(repo_dir.join("d.bin"))?, b"\0\x01\x02\xff\0");
+        assert_eq!(fs::read_to_string(repo_dir.join("b.txt"))?, "b\nb2\n");
+        assert_eq!(fs::read_to_string(repo_dir.join("c.txt"))?, "c\n");
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        assert_eq!(
+            git_output(root, &["log", "-1", "--format=%s"])?,
+            "Edit patch 0001-add-b.patch of //third_party/upstream\n"
+        );
+        assert_eq!(
+            git_output(root, &["rev-parse", "HEAD~1"])?.trim(),
+            head.trim()
+        );
+        ensure_git_clean(root)?;
+
+        // Nothing changed
+        let head = get_current_commit(root)?;
+        edit(Some("0003-add-c.patch"))?;
+        edit(None)?;
+        assert_eq!(get_current_commit(root)?, head);
+
+        // Conflicts are resolved like in updates
+        edit(Some("1"))?;
+        fs::write(repo_dir.join("b.txt"), "b0\n")?;
+        let res = edit(None);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        fs::write(repo_dir.join("b.txt"), "b0\nb2\n")?;
+        cont()?;
+        let rebased = fs::read_to_string(patches_dir.join("0002-edit-b.patch"))?;
+        assert!(rebased.contains(" b0\n+b2\n"), "{rebased}");
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        ensure_git_clean(root)?;
+
+        // Abort restores the previous state
+        let head = get_current_commit(root)?;
+        edit(Some("2"))?;
+        fs::write(repo_dir.join("b.txt"), "b3\n")?;
+        update(
+            UpdateCommandArgs {
+                abort: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(get_current_commit(root)?, head);
+        assert_eq!(fs::read_to_string(repo_dir.join("b.txt"))?, "b0\nb2\n");
+        assert_eq!(fs::read_to_string(repo_dir.join("c.txt"))?, "c\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_workspace() -> anyhow::Result<()> {
+        let (_upstream_dir, temp_dir, paths) = vendor_with_patches(&[
+            ("b.txt", "b\n", "Add b\n\nDetails of Add b"),
+            ("b.txt", "b\nb2\n", "Edit b\n\nDetails of Edit b"),
+            ("c.txt", "c\n", "Add c\n\nDetails of Add c"),
+        ])?;
+        let root = temp_dir.path();
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let repo_dir = target_dir.join("repo");
+        let patches_dir = target_dir.join("patches");
+        let add_b = fs::read(patches_dir.join("0001-add-b.patch"))?;
+
+        let workspace_dir = create_test_dir()?;
+        let ws = workspace_dir.path().join("ws");
+        workspace(
+            WorkspaceCommandArgs {
+                command: WorkspaceCommand::Open(WorkspaceOpenArgs {
+                    dir: Some(ws.clone()),
+                    path: Some(canonical_path.parse()?),
+                }),
+            },
+            &paths,
+        )?;
+        assert_eq!(workspace_root(Some(&ws))?.as_deref(), Some(root));
+        assert_eq!(workspace_root(Some(root))?, None);
+        assert_eq!(
+            git_output(&ws, &["log", "-4", "--format=%s"])?,
+            "Add c\nEdit b\nAdd b\nRelease v1\n"
+        );
+        assert_eq!(
+            git_output(&ws, &["log", "-1", "--format=%B", "HEAD~2"])?,
+            "Add b\n\nDetails of Add b\n\nDockyard-Patch: 0001-add-b.patch\n\n"
+        );
+        assert_eq!(fs::read_to_string(ws.join("b.txt"))?, "b\nb2\n");
+
+        let sync = || {
+            workspace(
+                WorkspaceCommandArgs {
+                    command: WorkspaceCommand::Sync(WorkspaceSyncArgs {
+                        dir: Some(ws.clone()),
+                    }),
+                },
+                &paths,
+            )
+        };
+
+        // Nothing changed
+        let head = get_current_commit(root)?;
+        sync()?;
+        assert_eq!(get_current_commit(root)?, head);
+
+        // Drop a commit, add a new one and cherry-pick from upstream
+        run_git(&ws, &["reset", "--quiet", "--hard", "HEAD~1"])?;
+        fs::write(ws.join("d.txt"), "d\n")?;
+        fs::write(ws.join("d.bin"), b"\0\x01\x02\xff\0")?;
+        run_git(&ws, &["add", "d.txt", "d.bin"])?;
+        run_git(
+            &ws,
+            &["commit", "--quiet", "-m", "Add d file\n\nWith details"],
+        )?;
+        run_git(&ws, &["cherry-pick", "v2"])?;
+        fs::write(ws.join("d.txt"), "uncommitted\n")?;
+        let res = sync();
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        run_git(&ws, &["checkout", "d.txt"])?;
+
+        sync()?;
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec![
+                "0001-add-b.patch",
+                "0002-edit-b.patch",
+                "0003-add-d-file.patch",
+                "0004-release-v2.patch"
+            ]
+        );
+        assert_eq!(fs::read(patches_dir.join("0001-add-b.patch"))?, add_b);
+        let add_d = fs::read_to_string(patches_dir.join("0003-add-d-file.patch"))?;
+        assert!(add_d.contains("Subject: [PATCH] Add d file\n"), "{add_d}");
+        assert!(add_d.contains("\nWith details\n"), "{add_d}");
+        assert!(!add_d.contains(PATCH_TRAILER), "{add_d}");
+        assert!(add_d.contains("GIT binary patch"), "{add_d}");
+        assert!(!repo_dir.join("c.txt").exists());
+        assert_eq!(fs::read_to_string(repo_dir.join("d.txt"))?, "d\n");
+        assert_eq!(fs::read(repo_dir.join("d.bin"))?, b"\0\x01\x02\xff\0");
+        assert_eq!(fs::read_to_string(repo_dir.join("a.txt"))?, "v2\n");
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        assert_eq!(
+            git_output(root, &["log", "-1", "--format=%s"])?,
+            "Sync patches of //third_party/upstream from workspace\n"
+        );
+        ensure_git_clean(root)?;
+
+        // Commits must stay on top of the vendored tree
+        run_git(&ws, &["checkout", "--quiet", "--orphan", "unrelated"])?;
+        run_git(&ws, &["commit", "--quiet", "-m", "Unrelated"])?;
+        let res = sync();
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_commands_with_series() -> anyhow::Result<()> {
+        let (_upstream_dir, temp_dir, paths) =
+            vendor_with_patches(&[("b.txt", "new\n", "Add b"), ("c.txt", "new\n", "Add c")])?;
+        let root = temp_dir.path();
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let patches_dir = target_dir.join("patches");
+        fs::write(
+            patches_dir.join(SERIES_FILE),
+            "# Local changes\n0001-add-b.patch  # needed by c\n\n# 0002-disabled.patch\n0002-add-c.patch\t# after b\n",
+        )?;
+        fs::write(patches_dir.join("0002-disabled.patch"), "")?;
+        commit_code("Disable patch", root)?;
+
+        let run = |command| {
+            patch(
+                PatchCommandArgs {
+                    path: Some(canonical_path.parse()?),
+                    command,
+                },
+                &paths,
+            )
+        };
+
+        run(PatchCommand::Move(PatchMoveArgs {
+            patch: "2".to_string(),
+            position: 1,
+        }))?;
+        assert_eq!(
+            fs::read_to_string(patches_dir.join(SERIES_FILE))?,
+            "# Local changes\n0002-add-c.patch\t# after b\n\n# 0002-disabled.patch\n0001-add-b.patch  # needed by c\n"
+        );
+
+        // Names clashing with disabled patches are refused
+        let res = run(PatchCommand::Rename(PatchRenameArgs {
+            patch: "0002-add-c.patch".to_string(),
+            name: "disabled".to_string(),
+        }));
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        run(PatchCommand::Drop(PatchDropArgs {
+            patch: "0002-add-c.patch".to_string(),
+        }))?;
+        assert_eq!(
+            fs::read_to_string(patches_dir.join(SERIES_FILE))?,
+            "# Local changes\n0001-add-b.patch  # needed by c\n\n# 0002-disabled.patch\n"
+        );
+        assert!(patches_dir.join("0002-disabled.patch").is_file());
+        assert!(!target_dir.join("repo/c.txt").exists());
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_series() -> anyhow::Result<()> {
+        let patches_dir = tempdir()?;
+        fs::write(patches_dir.path().join("0005-e"), "")?;
+        let entry = |name: &str, enabled| SeriesEntry {
+            name: name.to_string(),
+            enabled,
+        };
+        assert_eq!(
+            parse_series(
+                "# comment with words\n\n  0001-a.patch\n# 0002-b.patch\nfix.diff # trailing\n#\n\
+                 0003-c.patch\t# tab\n0004-d#e.patch\n# 3-way\n# 0005-e\n",
+                patches_dir.path()
+            )?,
+            vec![
+                entry("0001-a.patch", true),
+                entry("0002-b.patch", false),
+                entry("fix.diff", true),
+                entry("0003-c.patch", true),
+                entry("0004-d#e.patch", true),
+                entry("0005-e", false),
+            ]
+        );
+        assert_eq!(
+            split_series_comment("0001-a.patch\t# tab"),
+            ("0001-a.patch", "\t# tab")
+        );
+        assert_eq!(
+            split_series_comment("  # 0002-b.patch"),
+            ("", "# 0002-b.patch")
+        );
+
+        for series in [
+            "0001-a.patch -p0\n",
+            "0001-a.patch\n# 0001-a.patch\n",
+            "../0001-a.patch\n",
+            "series\n",
+        ] {
+            let res = parse_series(series, patches_dir.path());
+            assert!(res.is_err(), "Expected Err for {series:?}, but got {res:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_header_helpers() -> anyhow::Result<()> {
+        assert_eq!(
+            slugify("Fix build with musl!").as_deref(),
+            Some("fix-build-with-musl")
+        );
+        assert_eq!(
+            slugify("  --Ünïcode & CAPS-- ").as_deref(),
+            Some("n-code-caps")
+        );
+        assert_eq!(
+            slugify(&"a".repeat(100)).map(|s| s.len()),
+            Some(MAX_SLUG_LEN)
+        );
+        assert_eq!(slugify("???"), None);
+
+        assert_eq!(
+            format_rfc2822(0, "+0000")?,
+            "Thu, 1 Jan 1970 00:00:00 +0000"
+        );
+        assert_eq!(
+            format_rfc2822(1700000000, "+0100")?,
+            "Tue, 14 Nov 2023 23:13:20 +0100"
+        );
+        assert_eq!(
+            format_rfc2822(1709164800, "-0530")?,
+            "Wed, 28 Feb 2024 18:30:00 -0530"
+        );
+        assert!(format_rfc2822(0, "0100").is_err());
+
+        let patch = b"From: t <t@t>\nSubject: [PATCH] Title\n\n---\n a.txt | 2 +-\n\ndiff --git a/a.txt b/a.txt\n";
+        assert_eq!(
+            patch_header(patch),
+            b"From: t <t@t>\nSubject: [PATCH] Title\n\n---\n"
+        );
+        assert_eq!(patch_header(b"diff --git a/a.txt b/a.txt\n"), b"");
+        assert_eq!(
+            patch_header(b"Description\n--- a/a.txt\n"),
+            b"Description\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_patch_with_conflict_and_abort() -> anyhow::Result<()> {
+        let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line999
++line123
+ line2
+ line3
+",
+        )?;
+        commit_code("Create patch1", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        let apply_result = update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(
+            apply_result.is_err(),
+            "Expected Err, but got {:?}",
+            apply_result
+        );
+
+        update(
+            UpdateCommandArgs {
+                abort: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert!(
+            new_metadata.update_state.is_none(),
+            "Expected None, but got {:?}",
+            new_metadata.update_state
+        );
+        assert_eq!(new_metadata.revision.resolved_commit, "default".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_abort_keeps_unrelated_changes() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("src"))?;
+        fs::write(root.join("src/main.c"), "main\n")?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+
+        let prev_commit = get_current_commit(root)?;
+        let prev_tree_hash = hash_tree(&target_dir)?;
+        let dep_path: DependencyArg = canonical_path.parse()?;
+        let update_args = || UpdateCommandArgs {
+            version: Some("v2".to_string()),
+            path: Some(dep_path.clone()),
+            ..Default::default()
+        };
+        let abort_args = || UpdateCommandArgs {
+            abort: true,
+            path: Some(dep_path.clone()),
+            ..Default::default()
+        };
+
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_ne!(get_current_commit(root)?, prev_commit);
+
+        // Uncommitted change of a tracked file outside the dependency
+        fs::write(root.join("src/main.c"), "main changed\n")?;
+        let res = update(abort_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_some());
+        assert_eq!(
+            fs::read_to_string(root.join("src/main.c"))?,
+            "main changed\n"
+        );
+
+        // Staged change outside the dependency
+        run_git(root, &["checkout", "--", "src/main.c"])?;
+        fs::write(root.join("src/new.c"), "new\n")?;
+        run_git(root, &["add", "src/new.c"])?;
+        let res = update(abort_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        run_git(root, &["rm", "--cached", "--quiet", "src/new.c"])?;
+
+        // Untracked files outside the dependency are kept as is
+        let res = update(abort_args(), &paths);
+        assert!(res.is_ok(), "Expected Ok, but got {:?}", res);
+        assert_eq!(get_current_commit(root)?, prev_commit);
+        assert_eq!(hash_tree(&target_dir)?, prev_tree_hash);
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+        assert_eq!(fs::read_to_string(root.join("src/new.c"))?, "new\n");
+        let status_cmd = Command::new("git")
+            .current_dir(root)
+            .args(["status", "--porcelain"])
+            .output()?;
+        assert_eq!(String::from_utf8(status_cmd.stdout)?, "?? src/new.c\n");
+        fs::remove_file(root.join("src/new.c"))?;
+
+        // Commit outside the dependency made during the update
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        fs::write(root.join("src/main.c"), "main changed\n")?;
+        run_git(root, &["commit", "-m", "Unrelated", "src/main.c"])?;
+        let res = update(abort_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_some());
+        assert_eq!(
+            fs::read_to_string(root.join("src/main.c"))?,
+            "main changed\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_on_branch() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        fs::write(root.join(CONFIG_FILE), "[update]\nbranch = true\n")?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+
+        let original_branch = current_branch(root)?.unwrap();
+        let prev_commit = get_current_commit(root)?;
+        let update_branch = "dockyard/update/upstream-v2";
+        let dep_path: DependencyArg = canonical_path.parse()?;
+        let update_args = || UpdateCommandArgs {
+            version: Some("v2".to_string()),
+            path: Some(dep_path.clone()),
+            ..Default::default()
+        };
+
+        // Conflict on the update branch, abort returns to the original one
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_eq!(current_branch(root)?.as_deref(), Some(update_branch));
+        let state = load_metadata(&paths, &target_dir)?.update_state.unwrap();
+        assert_eq!(state.branch.as_deref(), Some(update_branch));
+        assert_eq!(state.original_branch.as_ref(), Some(&original_branch));
+        assert_eq!(get_commit(root, &original_branch)?, prev_commit);
+
+        update(
+            UpdateCommandArgs {
+                abort: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(current_branch(root)?.as_ref(), Some(&original_branch));
+        assert_eq!(get_current_commit(root)?, prev_commit);
+        assert!(get_commit(root, update_branch).is_err());
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+
+        // Completed update stays on the update branch
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        update(
+            UpdateCommandArgs {
+                skip: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(current_branch(root)?.as_deref(), Some(update_branch));
+        assert_eq!(get_commit(root, &original_branch)?, prev_commit);
+        assert_eq!(
+            get_commit(root, &format!("{update_branch}~3"))?,
+            prev_commit
+        );
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v2\n");
+
+        // Command line option overrides config
+        run_git(root, &["switch", "--quiet", &original_branch])?;
+        let res = update(
+            UpdateCommandArgs {
+                no_branch: true,
+                ..update_args()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_eq!(current_branch(root)?.as_ref(), Some(&original_branch));
+        assert_ne!(get_current_commit(root)?, prev_commit);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_squash() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        fs::write(root.join(".keep"), "")?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        fs::write(target_dir.join("repo/b.txt"), "new file\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream again", root)?;
+        let prev_commit = get_current_commit(root)?;
+
+        let res = update(
+            UpdateCommandArgs {
+                squash: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        fs::write(target_dir.join("repo/a.txt"), "v3 patched\n")?;
+        update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+
+        assert_eq!(get_commit(root, "HEAD~1")?, prev_commit);
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/a.txt"))?,
+            "v3 patched\n"
+        );
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/b.txt"))?,
+            "new file\n"
+        );
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+
+        let message = git_output(root, &["log", "-1", "--format=%B"])?;
+        let v1 = get_commit(upstream_dir.path(), "v1")?;
+        let v3 = get_commit(upstream_dir.path(), "HEAD")?;
+        assert_eq!(
+            message.trim(),
+            format!(
+                "Update {canonical_path} from v1 ({v1}) to {v3}
+
+Patches:
+  0001-change_name.patch - Applied
+  0002-change_name.patch - Resolved
+
+Upstream changes:
+  {} Release v3
+  {} Release v2",
+                &v3[..7],
+                get_commit(upstream_dir.path(), "--short=7 HEAD~1")?,
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_no_commit() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        fs::write(root.join(".keep"), "")?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        fs::write(target_dir.join("repo/b.txt"), "new file\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream again", root)?;
+
+        let prev_commit = get_current_commit(root)?;
+        let prev_tree_hash = hash_tree(&target_dir)?;
+        let dep_path: DependencyArg = canonical_path.parse()?;
+        let update_args = || UpdateCommandArgs {
+            no_commit: true,
+            path: Some(dep_path.clone()),
+            ..Default::default()
+        };
+
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert_eq!(get_current_commit(root)?, prev_commit);
+        let state = load_metadata(&paths, &target_dir)?.update_state.unwrap();
+        assert!(state.no_commit);
+        assert!(state.checkpoint_tree.is_some());
+        assert_eq!(state.patches[0].state, PatchState::Applied);
+        assert_eq!(state.patches[1].state, PatchState::Conflict);
+
+        update(
+            UpdateCommandArgs {
+                abort: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(get_current_commit(root)?, prev_commit);
+        assert_eq!(hash_tree(&target_dir)?, prev_tree_hash);
+        assert_eq!(git_output(root, &["status", "--porcelain"])?, "");
+
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        fs::write(target_dir.join("repo/a.txt"), "v3 patched\n")?;
+        update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+
+        assert_eq!(get_current_commit(root)?, prev_commit);
+        assert_eq!(git_output(root, &["diff", "--name-only"])?, "");
+        assert_eq!(
+            git_output(root, &["diff", "--cached", "--name-only"])?,
+            "third_party/upstream/dep_info.json
+third_party/upstream/patches/0002-change_name.patch
+third_party/upstream/repo/a.txt
+"
+        );
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+        assert_eq!(
+            normalize_patch(&fs::read_to_string(
+                target_dir.join("patches/0002-change_name.patch")
+            )?),
+            "diff --git a/a.txt b/a.txt
+--- a/a.txt
++++ b/a.txt
+@@ -1 +1 @@
+-v3
++v3 patched"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_commit_templates() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        fs::write(
+            root.join(CONFIG_FILE),
+            r#"[templates]
+import = "third_party: {path} {old_version} -> {new_version}\n\nChange-Type: update"
+patch_applied = "third_party: {{patch}} {index}/{count} {patch}"
+metadata_updated = "third_party: metadata {path}"
+"#,
+        )?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        fs::write(target_dir.join("repo/b.txt"), "new file\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+
+        update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
-        let target_dir = path_to_abs(&paths, "//third_party/repo_extract")?;
-        fs::create_dir_all(&target_dir)?;
-        fs::write(temp_dir.path().join(".keep"), "")?;
-        commit_code("Initial commit", temp_dir.path())?;
+        let v1 = get_commit(upstream_dir.path(), "v1")?;
+        let v2 = get_commit(upstream_dir.path(), "v2^{commit}")?;
+        assert_eq!(
+            git_output(root, &["log", "-3", "--format=%B"])?,
+            format!(
+                "third_party: metadata {canonical_path}
 
-        fs::write(target_dir.join("tesfile.txt"), "line1\nline2\n")?;
+third_party: {{patch}} 1/1 0001-change_name.patch
 
-        git_add_all(&paths.root)?;
-        let diff = extract_diff(&target_dir, &paths)?;
+third_party: {canonical_path} v1 ({v1}) -> v2 ({v2})
 
-        let diff_str = String::from_utf8_lossy(&diff);
+Change-Type: update
 
-        let expected_diff = "diff --git a/tesfile.txt b/tesfile.txt
-new file mode 100644
-index 0000000..c0d0fb4
---- /dev/null
-+++ b/tesfile.txt
-@@ -0,0 +1,2 @@
-+line1
-+line2
-";
-        assert_eq!(diff_str, expected_diff);
+"
+            )
+        );
+
+        fs::write(
+            root.join(CONFIG_FILE),
+            "[templates]\nimport = \"{version}\"\n",
+        )?;
+        let res = MonorepoPaths::from_dir(root);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res.map(|_| ()));
 
         Ok(())
     }
 
     #[test]
-    fn test_extract_patch_with_repo_subdir() -> anyhow::Result<()> {
-        // Git expects forward slashes ('/') as the path separator. So let's check that the
-        // functions works properly with possible backslashes after PahtBuf::join as well.
+    fn test_config_layout_and_allowed_hosts() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        init_git(root)?;
+        fs::create_dir_all(root.join("vendor"))?;
+        fs::write(
+            root.join(CONFIG_FILE),
+            r#"third_party = "vendor"
+
+[layout]
+repo = "src"
+patches = "diffs"
+metadata = "DEPENDENCY.json"
+
+[upstream]
+allowed_hosts = ["github.com", "*.example.com"]
+"#,
+        )?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        assert_eq!(paths.roots.len(), 1);
+        assert_eq!(paths.roots[0].name, "third_party");
+        assert_eq!(paths.roots[0].path, root.join("vendor"));
+        let canonical_path = "//vendor/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        let res = vendor(
+            VendorCommandArgs {
+                git: Some("https://gitlab.com/example/repo.git".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(!target_dir.exists());
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        assert_eq!(fs::read_to_string(target_dir.join("src/a.txt"))?, "v1\n");
+        assert!(target_dir.join("DEPENDENCY.json").is_file());
+
+        fs::write(target_dir.join("src/b.txt"), "new file\n")?;
+        run_git(root, &["add", "."])?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", root)?;
+        assert!(target_dir.join("diffs/0001-change_name.patch").is_file());
+
+        update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some(canonical_path.parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(fs::read_to_string(target_dir.join("src/a.txt"))?, "v2\n");
+        assert_eq!(
+            fs::read_to_string(target_dir.join("src/b.txt"))?,
+            "new file\n"
+        );
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        let deps = load_dependencies(&paths)?;
+        assert_eq!(deps.len(), 1);
+        assert_eq!(deps[0].path, canonical_path);
+        assert_eq!(deps[0].patches, 1);
+
+        let upstream = &paths.config.upstream;
+        upstream.check_url("https://github.com/example/repo.git")?;
+        upstream.check_url("git@github.com:example/repo.git")?;
+        upstream.check_url("ssh://git@git.example.com:2222/repo.git")?;
+        upstream.check_url("file:///srv/repo.git")?;
+        upstream.check_url("/srv/repo.git")?;
+        assert!(
+            upstream
+                .check_url("https://github.com.evil.org/repo.git")
+                .is_err()
+        );
+        assert!(
+            upstream
+                .check_url("git@gitlab.com:example/repo.git")
+                .is_err()
+        );
+
+        for config in [
+            "unknown = true\n",
+            "third_party = \"../vendor\"\n",
+            "[layout]\nrepo = \"src/code\"\n",
+            "[layout]\npatches = \"repo\"\n",
+            "[update]\nno_commit = true\nsquash = true\n",
+            "[upstream]\nallowed_hosts = [\"[\"]\n",
+        ] {
+            fs::write(root.join(CONFIG_FILE), config)?;
+            let res = MonorepoPaths::from_dir(root);
+            assert!(res.is_err(), "Expected Err for {config:?}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_apply_patch_with_conflict_and_skip() -> anyhow::Result<()> {
         let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
-        let target_dir = path_to_abs(&paths, "//third_party/repo_extract_backslash")?;
-        let repo_dir = target_dir.join("repo");
-        fs::create_dir_all(&repo_dir)?;
+        let target_dir = temp_dir.path().join("third_party/example");
+
+        let mut metadata = DependencyMetadata {
+            url: "empty".to_string(),
+            revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "default".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            tag_pattern: None,
+            patched_tree_hash: None,
+            archive: None,
+            subdir: None,
+            filter: FileFilter::default(),
+            update_state: None,
+        };
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        fs::write(
+            target_dir.join("repo/a.txt"),
+            "line1
+line2
+line3
+",
+        )?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let canonical_path = "//third_party/example";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+
+        fs::write(
+            target_dir.join("patches/0001-update-line1.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,3 @@
+-line999
++line123
+ line2
+ line3
+",
+        )?;
+        fs::write(
+            target_dir.join("patches/0002-add-line4.patch"),
+            "diff --git a/a.txt b/a.txt
+index 83db48f..efc6926 100644
+--- a/a.txt
++++ b/a.txt
+@@ -1,3 +1,4 @@
+ line1
+ line2
+ line3
++line4
+",
+        )?;
+        commit_code("Create patches", &target_dir)?;
+
+        // make all patches pending
+        metadata.update_state = Some(UpdateState {
+            prev_commit_hash: get_current_commit(&paths.root)?,
+            new_revision: UpstreamRevision {
+                requested_ref: None,
+                resolved_commit: "12345".to_string(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            },
+            patches: load_patch_list(&paths, &target_dir)?
+                .iter()
+                .map(|e| PatchApplyState {
+                    name: e.clone(),
+                    state: PatchState::Pending,
+                })
+                .collect(),
+            branch: None,
+            original_branch: None,
+            squash: false,
+            upstream_shortlog: vec![],
+            no_commit: false,
+            snapshot_tree: None,
+            checkpoint_tree: None,
+            edited_patch: None,
+        });
+        update_metadata(&paths, &target_dir, &metadata)?;
+
+        let apply_result = update(
+            UpdateCommandArgs {
+                cont: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(
+            apply_result.is_err(),
+            "Expected Err, but got {:?}",
+            apply_result
+        );
+
+        // leftovers of a manual resolution attempt must be dropped
+        fs::write(target_dir.join("repo/a.txt.rej"), "rejected hunk")?;
+        fs::write(target_dir.join("repo/a.txt"), "garbage")?;
+
+        update(
+            UpdateCommandArgs {
+                skip: true,
+                path: Some("//third_party/example".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+
+        let new_metadata = load_metadata(&paths, &target_dir)?;
+        assert!(
+            new_metadata.update_state.is_none(),
+            "Expected None, but got {:?}",
+            new_metadata.update_state
+        );
+        assert_eq!(new_metadata.revision.resolved_commit, "12345".to_string());
+
+        assert!(!target_dir.join("patches/0001-update-line1.patch").exists());
+        assert!(!target_dir.join("repo/a.txt.rej").exists());
+        assert_eq!(
+            load_patch_list(&paths, &target_dir)?,
+            vec!["0002-add-line4.patch"]
+        );
+
+        let content = fs::read_to_string(target_dir.join("repo/a.txt"))?;
+        assert_eq!(
+            content,
+            "line1
+line2
+line3
+line4
+"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vendor_and_update_to_version() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
         fs::write(temp_dir.path().join(".keep"), "")?;
         commit_code("Initial commit", temp_dir.path())?;
 
-        fs::write(repo_dir.join("tesfile.txt"), "line1\nline2\n")?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/upstream")?;
 
-        git_add_all(&paths.root)?;
-        let diff = extract_diff(&repo_dir, &paths)?;
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", temp_dir.path())?;
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v1\n");
+        assert!(!target_dir.join("repo/.git").exists());
 
-        let diff_str = String::from_utf8_lossy(&diff);
+        update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v2\n");
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.requested_ref.as_deref(), Some("v2"));
+        assert_eq!(metadata.revision.tracked_branch, None);
+        assert_eq!(
+            metadata.revision.resolved_commit,
+            get_commit(upstream_dir.path(), "v2^{commit}")?
+        );
 
-        let expected_diff = "diff --git a/tesfile.txt b/tesfile.txt
-new file mode 100644
-index 0000000..c0d0fb4
---- /dev/null
-+++ b/tesfile.txt
-@@ -0,0 +1,2 @@
-+line1
-+line2
-";
-        assert_eq!(diff_str, expected_diff);
+        // Without version the upstream HEAD is used
+        update(
+            UpdateCommandArgs {
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v3\n");
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.requested_ref, None);
+        assert_eq!(
+            metadata.revision.resolved_commit,
+            get_current_commit(upstream_dir.path())?
+        );
+        assert_eq!(
+            metadata.revision.tracked_branch,
+            Some(get_commit(upstream_dir.path(), "--abbrev-ref HEAD")?)
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_extract_patch_error_on_untracked() -> anyhow::Result<()> {
+    fn test_update_to_unknown_version() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
         let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
-        let target_dir = path_to_abs(&paths, "//third_party/repo_untracked")?;
-        fs::create_dir_all(&target_dir)?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/upstream")?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", temp_dir.path())?;
+
+        let res = update(
+            UpdateCommandArgs {
+                version: Some("v999".to_string()),
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+
+        // The vendored code stays untouched
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v1\n");
+        assert!(!target_dir.join(".upstream").exists());
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.requested_ref.as_deref(), Some("v1"));
+        assert!(metadata.update_state.is_none());
+        ensure_git_clean(&paths.root)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_follows_tracked_branch() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/upstream")?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("release".to_string()),
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", temp_dir.path())?;
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v2\n");
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.tracked_branch.as_deref(), Some("release"));
+
+        let dep_path: DependencyArg = "//third_party/upstream".parse()?;
+
+        let update_args = || UpdateCommandArgs {
+            path: Some(dep_path.clone()),
+            ..Default::default()
+        };
+
+        let res = update(update_args(), &paths);
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
 
-        fs::write(target_dir.join("tesfile.txt"), "line1\nline2\n")?;
+        run_git(upstream_dir.path(), &["checkout", "--quiet", "release"])?;
+        fs::write(upstream_dir.path().join("a.txt"), "v2.1\n")?;
+        commit_code("Release v2.1", upstream_dir.path())?;
 
-        let res = extract_diff(&target_dir, &paths);
-        assert!(res.is_err(), "Expected Err, but get {:?}", res);
+        update(update_args(), &paths)?;
+        assert_eq!(fs::read_to_string(target_dir.join("repo/a.txt"))?, "v2.1\n");
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.tracked_branch.as_deref(), Some("release"));
+        assert_eq!(
+            metadata.revision.resolved_commit,
+            get_current_commit(upstream_dir.path())?
+        );
 
         Ok(())
     }
 
     #[test]
-    fn test_update_apply_patches() -> anyhow::Result<()> {
+    fn test_load_legacy_metadata() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
         let temp_dir = create_test_dir()?;
-
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
         let target_dir = temp_dir.path().join("third_party/example");
 
-        let mut metadata = DependencyMetadata {
-            url: "empty".to_string(),
-            version: "default".to_string(),
-            update_state: None,
+        let write_legacy = |url: &str, version: &str, new_version: &str| {
+            fs::write(
+                paths.metadata_file(&target_dir),
+                format!(
+                    r#"{{
+  "url": "{url}",
+  "version": "{version}",
+  "update_state": {{
+    "prev_commit_hash": "abc",
+    "new_version": "{new_version}",
+    "patches": []
+  }}
+}}"#
+                ),
+            )
         };
-        update_metadata(&target_dir, &metadata)?;
-
-        fs::write(
-            target_dir.join("repo/a.txt"),
-            "line1
-line2
-line3
-",
-        )?;
-        commit_code("Initial commit", &temp_dir.path())?;
-
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
-
-        let canonical_path = "//third_party/example";
-        let target_dir = path_to_abs(&paths, canonical_path)?;
-
-        fs::write(
-            target_dir.join("patches/0001-update-line1.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line1
-+line123
- line2
- line3
-",
-        )?;
-        commit_code("Create patch1", &target_dir)?;
+        let missing_url = temp_dir
+            .path()
+            .join("missing")
+            .to_string_lossy()
+            .to_string();
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        // Tags can't be resolved without the upstream, the file is kept as is
+        write_legacy(&missing_url, "v1", "v2")?;
+        let legacy = fs::read_to_string(paths.metadata_file(&target_dir))?;
+        assert!(load_metadata(&paths, &target_dir).is_err());
+        assert_eq!(
+            fs::read_to_string(paths.metadata_file(&target_dir))?,
+            legacy
+        );
 
-        // make all patches pending
-        metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: "12345".to_string(),
-            patches: load_patch_list(&target_dir)?
-                .iter()
-                .map(|e| PatchApplyState {
-                    name: e.clone(),
-                    state: PatchState::Pending,
-                })
-                .collect(),
-        });
-        update_metadata(&target_dir, &metadata)?;
+        write_legacy(&upstream_url, "v1", "v2")?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        let v1_commit = get_commit(upstream_dir.path(), "v1")?;
+        let v2_commit = get_commit(upstream_dir.path(), "v2^{commit}")?;
+        assert_eq!(
+            metadata.revision,
+            UpstreamRevision {
+                requested_ref: Some("v1".to_string()),
+                resolved_commit: v1_commit.clone(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            }
+        );
+        assert_eq!(
+            metadata.update_state.unwrap().new_revision,
+            UpstreamRevision {
+                requested_ref: Some("v2".to_string()),
+                resolved_commit: v2_commit.clone(),
+                tracked_branch: None,
+                upstream_tree_hash: None,
+            }
+        );
 
-        apply_patches(&target_dir, canonical_path, &paths, &mut metadata)?;
+        // The file is upgraded in place
+        let content: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(paths.metadata_file(&target_dir))?)?;
+        assert!(content.get("version").is_none());
+        assert_eq!(content["requested_ref"], "v1");
+        assert_eq!(content["resolved_commit"], v1_commit);
+        assert_eq!(
+            content["update_state"]["new_revision"]["resolved_commit"],
+            v2_commit
+        );
 
-        let new_metadata = load_metadata(&target_dir)?;
+        // Commits, even abbreviated, don't need the upstream
+        write_legacy(&missing_url, &v1_commit, &v2_commit[..7])?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.revision.resolved_commit, v1_commit);
+        assert_eq!(metadata.revision.requested_ref, None);
         assert_eq!(
-            new_metadata.update_state.clone().unwrap().patches[0].state,
-            PatchState::Applied,
-            "expected Applied state for the patch, got {:?}",
-            new_metadata.update_state.unwrap()
+            metadata.update_state.unwrap().new_revision.resolved_commit,
+            v2_commit[..7]
         );
 
-        let content = fs::read_to_string(target_dir.join("repo/a.txt"))?;
+        // Branches keep being tracked
+        write_legacy(&upstream_url, "release", "v2")?;
+        let metadata = load_metadata(&paths, &target_dir)?;
         assert_eq!(
-            content,
-            "line123
-line2
-line3
-"
+            metadata.revision,
+            UpstreamRevision {
+                requested_ref: Some("release".to_string()),
+                resolved_commit: v2_commit,
+                tracked_branch: Some("release".to_string()),
+                upstream_tree_hash: None,
+            }
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_update_apply_multiple_patches() -> anyhow::Result<()> {
+    fn test_list_dependencies() -> anyhow::Result<()> {
         let temp_dir = create_test_dir()?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
 
-        let target_dir = temp_dir.path().join("third_party/example");
-
-        let mut metadata = DependencyMetadata {
-            url: "empty".to_string(),
-            version: "default".to_string(),
-            update_state: None,
+        let revision = UpstreamRevision {
+            requested_ref: Some("v1".to_string()),
+            resolved_commit: "12345".to_string(),
+            tracked_branch: None,
+            upstream_tree_hash: None,
         };
-        update_metadata(&target_dir, &metadata)?;
-
+        for (path, update_state) in [
+            ("third_party/example", None),
+            (
+                "third_party/org/nested",
+                Some(UpdateState {
+                    prev_commit_hash: "abc".to_string(),
+                    new_revision: revision.clone(),
+                    patches: vec![],
+                    branch: None,
+                    original_branch: None,
+                    squash: false,
+                    upstream_shortlog: vec![],
+                    no_commit: false,
+                    snapshot_tree: None,
+                    checkpoint_tree: None,
+                    edited_patch: None,
+                }),
+            ),
+        ] {
+            let target_dir = temp_dir.path().join(path);
+            fs::create_dir_all(&target_dir)?;
+            update_metadata(
+                &paths,
+                &target_dir,
+                &DependencyMetadata {
+                    url: format!("https://example.com/{path}.git"),
+                    revision: revision.clone(),
+                    tag_pattern: None,
+                    patched_tree_hash: None,
+                    archive: None,
+                    subdir: None,
+                    filter: FileFilter::default(),
+                    update_state,
+                },
+            )?;
+        }
         fs::write(
-            target_dir.join("repo/a.txt"),
-            "line1
-line2
-line3
-",
+            temp_dir
+                .path()
+                .join("third_party/example/patches/0001-a.patch"),
+            "",
+        )?;
+        // Metadata files inside of vendored code don't count
+        fs::create_dir_all(temp_dir.path().join("third_party/example/repo/inner"))?;
+        fs::write(
+            temp_dir
+                .path()
+                .join("third_party/example/repo/inner")
+                .join(&paths.config.layout.metadata),
+            "{}",
         )?;
-        commit_code("Initial commit", &temp_dir.path())?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
+        // Broken dependencies are reported without failing the others
+        let broken_dir = temp_dir.path().join("third_party/broken");
+        fs::create_dir_all(&broken_dir)?;
+        fs::write(paths.metadata_file(&broken_dir), "{")?;
+
+        let deps = load_dependencies(&paths)?;
+        let summary: Vec<_> = deps
+            .iter()
+            .map(|d| {
+                (
+                    d.path.as_str(),
+                    d.patches,
+                    d.update_in_progress,
+                    d.error.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("//third_party/broken", 0, false, true),
+                ("//third_party/example", 1, false, false),
+                ("//third_party/org/nested", 0, true, false),
+            ]
+        );
+        assert_eq!(
+            deps[2].url.as_deref(),
+            Some("https://example.com/third_party/org/nested.git")
+        );
 
-        let canonical_path = "//third_party/example";
-        let target_dir = path_to_abs(&paths, canonical_path)?;
+        Ok(())
+    }
 
-        fs::write(
-            target_dir.join("patches/0001-update-line1.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line1
-+line123
- line2
- line3
-",
-        )?;
-        commit_code("Create patch1", &target_dir)?;
+    #[test]
+    fn test_list_multiple_third_party_roots() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
 
+        let temp_dir = tempdir()?;
+        let root = temp_dir.path();
+        init_git(root)?;
+        for dir in ["third_party", "vendor/go", "external/js"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
         fs::write(
-            target_dir.join("patches/0002-update-line4.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line123
- line2
- line3
-+line4
-",
+            root.join(CONFIG_FILE),
+            r#"[roots]
+third_party = "third_party"
+go = "vendor/go"
+js = "external/js"
+"#,
         )?;
-        commit_code("Create patch2", &target_dir)?;
-
-        // make all patches pending
-        metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: "12345".to_string(),
-            patches: load_patch_list(&target_dir)?
-                .iter()
-                .map(|e| PatchApplyState {
-                    name: e.clone(),
-                    state: PatchState::Pending,
-                })
-                .collect(),
-        });
-        update_metadata(&target_dir, &metadata)?;
-
-        apply_patches(&target_dir, canonical_path, &paths, &mut metadata)?;
+        commit_code("Initial commit", root)?;
+
+        let paths = MonorepoPaths::from_dir(root)?;
+        for canonical_path in ["//vendor/go/upstream", "//external/js/org/upstream"] {
+            vendor(
+                VendorCommandArgs {
+                    git: Some(upstream_url.clone()),
+                    version: Some("v1".to_string()),
+                    path: canonical_path.parse()?,
+                    ..Default::default()
+                },
+                &paths,
+            )?;
+        }
+        commit_code("Vendor upstream", root)?;
 
-        let new_metadata = load_metadata(&target_dir)?;
+        let deps = load_dependencies(&paths)?;
+        let deps: Vec<_> = deps
+            .iter()
+            .map(|dep| (dep.path.as_str(), dep.root.as_str()))
+            .collect();
         assert_eq!(
-            new_metadata.update_state.clone().unwrap().patches[0].state,
-            PatchState::Applied,
-            "expected Applied state for the patch, got {:?}",
-            new_metadata.update_state.unwrap()
+            deps,
+            vec![
+                ("//external/js/org/upstream", "js"),
+                ("//vendor/go/upstream", "go"),
+            ]
         );
         assert_eq!(
-            new_metadata.update_state.clone().unwrap().patches[1].state,
-            PatchState::Applied,
-            "expected Applied state for the patch, got {:?}",
-            new_metadata.update_state.unwrap()
+            dependency_dirs(&paths, None)?,
+            vec![
+                root.join("external/js/org/upstream"),
+                root.join("vendor/go/upstream"),
+            ]
         );
-
-        let content = fs::read_to_string(target_dir.join("repo/a.txt"))?;
-        assert_eq!(
-            content,
-            "line2
-line3
-line4
-"
+        assert!(
+            check_upstream(&paths, &root.join("vendor/go/upstream"))?.outdated,
+            "Expected v1 to lag behind upstream"
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_update_apply_patch_with_conflict() -> anyhow::Result<()> {
-        let temp_dir = create_test_dir()?;
+    fn test_outdated() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = format!("file://{}", upstream_dir.path().display());
 
-        let target_dir = temp_dir.path().join("third_party/example");
+        let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
 
-        let mut metadata = DependencyMetadata {
-            url: "empty".to_string(),
-            version: "default".to_string(),
-            update_state: None,
-        };
-        update_metadata(&target_dir, &metadata)?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
 
-        fs::write(
-            target_dir.join("repo/a.txt"),
-            "line1
-line2
-line3
-",
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                tag_pattern: Some("v*".to_string()),
+                path: "//third_party/tagged".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: None,
+                path: "//third_party/head".parse()?,
+                ..Default::default()
+            },
+            &paths,
         )?;
-        commit_code("Initial commit", &temp_dir.path())?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
+        let status = check_upstream(&paths, &path_to_abs(&paths, "//third_party/tagged")?)?;
+        assert!(status.outdated, "Expected outdated, got {:?}", status);
+        assert_eq!(status.available_ref.as_deref(), Some("v2"));
+        assert_eq!(
+            status.available_commit,
+            Some(get_commit(upstream_dir.path(), "v2^{commit}")?)
+        );
 
-        let canonical_path = "//third_party/example";
-        let target_dir = path_to_abs(&paths, canonical_path)?;
+        let head_dir = path_to_abs(&paths, "//third_party/head")?;
+        let status = check_upstream(&paths, &head_dir)?;
+        assert!(!status.outdated, "Expected up to date, got {:?}", status);
 
-        fs::write(
-            target_dir.join("patches/0001-update-line1.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line999
-+line123
- line2
- line3
-",
+        fs::write(upstream_dir.path().join("a.txt"), "v4\n")?;
+        commit_code("Release v4", upstream_dir.path())?;
+
+        let status = check_upstream(&paths, &head_dir)?;
+        assert!(status.outdated, "Expected outdated, got {:?}", status);
+        assert_eq!(
+            status.available_commit,
+            Some(get_current_commit(upstream_dir.path())?)
+        );
+
+        let res = outdated(
+            OutdatedCommandArgs {
+                format: OutputFormat::Text,
+                path: None,
+            },
+            &paths,
         )?;
-        commit_code("Create patch1", &target_dir)?;
+        assert!(!res);
+
+        // Broken dependencies are reported along with the others
+        let broken_dir = path_to_abs(&paths, "//third_party/broken")?;
+        fs::create_dir_all(&broken_dir)?;
+        fs::write(paths.metadata_file(&broken_dir), "{")?;
+        let status = check_upstream(&paths, &broken_dir)?;
+        assert!(status.error.is_some(), "Expected error, got {:?}", status);
+        assert!(status.current.is_none());
+        let res = outdated(
+            OutdatedCommandArgs {
+                format: OutputFormat::Json,
+                path: None,
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
 
-        // make all patches pending
-        metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: "12345".to_string(),
-            patches: load_patch_list(&target_dir)?
-                .iter()
-                .map(|e| PatchApplyState {
-                    name: e.clone(),
-                    state: PatchState::Pending,
-                })
-                .collect(),
-        });
-        update_metadata(&target_dir, &metadata)?;
+        Ok(())
+    }
 
-        let apply_result = update(
-            UpdateCommandArgs {
-                version: None,
-                force: false,
-                status: false,
-                cont: true,
-                abort: false,
-                path: Some("//third_party/example".to_string()),
+    #[test]
+    fn test_verify() -> anyhow::Result<()> {
+        let upstream_dir = create_upstream_repo()?;
+        let upstream_url = upstream_dir.path().to_string_lossy().to_string();
+
+        let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/upstream")?;
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
             },
             &paths,
+        )?;
+        commit_code("Vendor upstream", temp_dir.path())?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert!(metadata.patched_tree_hash.is_some());
+        assert_eq!(
+            metadata.patched_tree_hash,
+            metadata.revision.upstream_tree_hash
         );
-        assert!(
-            apply_result.is_err(),
-            "Expected Err, but got {:?}",
-            apply_result
+
+        fs::write(target_dir.join("repo/a.txt"), "v1 patched\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", temp_dir.path())?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_ne!(
+            metadata.patched_tree_hash,
+            metadata.revision.upstream_tree_hash
         );
 
-        let new_metadata = load_metadata(&target_dir)?;
-        assert_eq!(
-            new_metadata.update_state.clone().unwrap().patches[0].state,
-            PatchState::Conflict,
-            "expected Conflict state for the patch, got {:?}",
-            new_metadata.update_state.unwrap()
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
+        assert!(verify_dependency_offline(&paths, &target_dir)?);
+
+        fs::write(target_dir.join("repo/a.txt"), "v1 edited\n")?;
+        fs::write(target_dir.join("repo/b.txt"), "new file\n")?;
+
+        assert_eq!(
+            verify_dependency(&paths, &target_dir)?,
+            vec![
+                TreeDiff::Modified(PathBuf::from("a.txt")),
+                TreeDiff::Added(PathBuf::from("b.txt")),
+            ]
+        );
+        assert!(!verify_dependency_offline(&paths, &target_dir)?);
+        let res = verify(
+            VerifyCommandArgs {
+                offline: false,
+                path: Some("//third_party/upstream".parse()?),
+            },
+            &paths,
         );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
 
         Ok(())
     }
 
     #[test]
-    fn test_update_apply_patch_with_conflict_and_continue() -> anyhow::Result<()> {
-        let temp_dir = create_test_dir()?;
-
-        let target_dir = temp_dir.path().join("third_party/example");
-
-        let mut metadata = DependencyMetadata {
-            url: "empty".to_string(),
-            version: "default".to_string(),
-            update_state: None,
-        };
-        update_metadata(&target_dir, &metadata)?;
-
-        fs::write(
-            target_dir.join("repo/a.txt"),
-            "line1
-line2
-line3
-",
-        )?;
-        commit_code("Initial commit", &temp_dir.path())?;
-
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
+    fn test_vendor_and_update_subdir() -> anyhow::Result<()> {
+        let upstream_dir = tempdir()?;
+        let upstream_path = upstream_dir.path();
+        init_git(upstream_path)?;
+        fs::create_dir_all(upstream_path.join("libfoo/src"))?;
+        fs::create_dir_all(upstream_path.join("other"))?;
+        fs::write(upstream_path.join("README"), "root\n")?;
+        fs::write(upstream_path.join("other/b.txt"), "other\n")?;
+        fs::write(upstream_path.join("libfoo/src/foo.c"), "a\nb\nc\n")?;
+        run_git(upstream_path, &["add", "."])?;
+        run_git(upstream_path, &["commit", "-m", "v1"])?;
+        run_git(upstream_path, &["tag", "v1"])?;
+        fs::write(upstream_path.join("libfoo/src/foo.c"), "a\nb\nc\nd\n")?;
+        fs::write(upstream_path.join("other/b.txt"), "other v2\n")?;
+        run_git(upstream_path, &["commit", "-am", "v2"])?;
+        run_git(upstream_path, &["tag", "v2"])?;
+        let upstream_url = upstream_path.to_string_lossy().to_string();
 
-        let canonical_path = "//third_party/example";
-        let target_dir = path_to_abs(&paths, canonical_path)?;
-
-        fs::write(
-            target_dir.join("patches/0001-update-line1.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line999
-+line123
- line2
- line3
-",
-        )?;
-        commit_code("Create patch1", &target_dir)?;
+        let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
 
-        // make all patches pending
-        metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: "12345".to_string(),
-            patches: load_patch_list(&target_dir)?
-                .iter()
-                .map(|e| PatchApplyState {
-                    name: e.clone(),
-                    state: PatchState::Pending,
-                })
-                .collect(),
-        });
-        update_metadata(&target_dir, &metadata)?;
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/libfoo")?;
 
-        let apply_result = update(
-            UpdateCommandArgs {
-                version: None,
-                force: false,
-                status: false,
-                cont: true,
-                abort: false,
-                path: Some("//third_party/example".to_string()),
+        let res = vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                subdir: Some("../libfoo".to_string()),
+                path: "//third_party/libfoo".parse()?,
+                ..Default::default()
             },
             &paths,
         );
-        assert!(
-            apply_result.is_err(),
-            "Expected Err, but got {:?}",
-            apply_result
-        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(!target_dir.exists());
+
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                subdir: Some("/libfoo/".to_string()),
+                path: "//third_party/libfoo".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor libfoo", temp_dir.path())?;
 
-        let new_metadata = load_metadata(&target_dir)?;
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(metadata.subdir.as_deref(), Some("libfoo"));
         assert_eq!(
-            new_metadata.update_state.clone().unwrap().patches[0].state,
-            PatchState::Conflict,
-            "expected Conflict state for the patch, got {:?}",
-            new_metadata.update_state.unwrap()
+            list_files(&target_dir.join("repo"))?,
+            vec![PathBuf::from("src/foo.c")]
         );
 
-        fs::write(
-            target_dir.join("repo/a.txt"),
-            "line333
-line2
-line3
-",
+        fs::write(target_dir.join("repo/src/foo.c"), "patched\nb\nc\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some("//third_party/libfoo".parse()?),
+                ..Default::default()
+            },
+            &paths,
         )?;
+        commit_code("Patch libfoo", temp_dir.path())?;
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
 
         update(
             UpdateCommandArgs {
-                version: None,
-                force: false,
-                status: false,
-                cont: true,
-                abort: false,
-                path: Some("//third_party/example".to_string()),
+                version: Some("v2".to_string()),
+                path: Some("//third_party/libfoo".parse()?),
+                ..Default::default()
             },
             &paths,
         )?;
 
-        let new_metadata = load_metadata(&target_dir)?;
-        assert!(
-            new_metadata.update_state.is_none(),
-            "Expected None, but got {:?}",
-            new_metadata.update_state
+        assert_eq!(
+            list_files(&target_dir.join("repo"))?,
+            vec![PathBuf::from("src/foo.c")]
         );
-        assert_eq!(new_metadata.version, "12345".to_string());
-
-        let patch_content = fs::read_to_string(target_dir.join("patches/0001-update-line1.patch"))?;
         assert_eq!(
-            normalize_patch(&patch_content),
-            "diff --git a/a.txt b/a.txt
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line1
-+line333
- line2
- line3",
+            fs::read_to_string(target_dir.join("repo/src/foo.c"))?,
+            "patched\nb\nc\nd\n"
         );
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
 
         Ok(())
     }
 
     #[test]
-    fn test_update_apply_patch_with_conflict_and_abort() -> anyhow::Result<()> {
+    fn test_vendor_and_update_with_file_filter() -> anyhow::Result<()> {
+        let upstream_dir = tempdir()?;
+        let upstream_path = upstream_dir.path();
+        init_git(upstream_path)?;
+        for dir in ["src/img", "tests", "docs"] {
+            fs::create_dir_all(upstream_path.join(dir))?;
+        }
+        fs::write(upstream_path.join("src/a.c"), "a\nb\nc\n")?;
+        fs::write(upstream_path.join("src/img/logo.png"), "png")?;
+        fs::write(upstream_path.join("tests/test.c"), "test v1\n")?;
+        fs::write(upstream_path.join("docs/index.md"), "docs\n")?;
+        fs::write(upstream_path.join("README"), "readme\n")?;
+        run_git(upstream_path, &["add", "."])?;
+        run_git(upstream_path, &["commit", "-m", "v1"])?;
+        run_git(upstream_path, &["tag", "v1"])?;
+        fs::write(upstream_path.join("src/a.c"), "a\nb\nc\nd\n")?;
+        fs::write(upstream_path.join("tests/test.c"), "test v2\n")?;
+        run_git(upstream_path, &["commit", "-am", "v2"])?;
+        run_git(upstream_path, &["tag", "v2"])?;
+        let upstream_url = upstream_path.to_string_lossy().to_string();
+
         let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
 
-        let target_dir = temp_dir.path().join("third_party/example");
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/upstream")?;
 
-        let mut metadata = DependencyMetadata {
-            url: "empty".to_string(),
-            version: "default".to_string(),
-            update_state: None,
-        };
-        update_metadata(&target_dir, &metadata)?;
+        let res = vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                exclude: vec!["[".to_string()],
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        );
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(!target_dir.exists());
 
-        fs::write(
-            target_dir.join("repo/a.txt"),
-            "line1
-line2
-line3
-",
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_url.clone()),
+                version: Some("v1".to_string()),
+                include: vec!["src".to_string(), "tests".to_string()],
+                exclude: vec!["tests".to_string(), "**/*.png".to_string()],
+                path: "//third_party/upstream".parse()?,
+                ..Default::default()
+            },
+            &paths,
         )?;
-        commit_code("Initial commit", &temp_dir.path())?;
+        commit_code("Vendor upstream", temp_dir.path())?;
 
-        let paths = paths::MonorepoPaths::from_dir(temp_dir.path())
-            .context("Could not find monorepo checkout paths")?;
+        let repo_dir = target_dir.join("repo");
+        assert_eq!(list_files(&repo_dir)?, vec![PathBuf::from("src/a.c")]);
+        assert!(!repo_dir.join("src/img").exists());
+        assert!(!repo_dir.join("tests").exists());
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            metadata.revision.upstream_tree_hash,
+            Some(hash_tree(&repo_dir)?)
+        );
+        let dep_info: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(paths.metadata_file(&target_dir))?)?;
+        assert_eq!(dep_info["include"], serde_json::json!(["src", "tests"]));
+        assert_eq!(
+            dep_info["exclude"],
+            serde_json::json!(["tests", "**/*.png"])
+        );
 
-        let canonical_path = "//third_party/example";
-        let target_dir = path_to_abs(&paths, canonical_path)?;
+        fs::write(repo_dir.join("src/a.c"), "patched\nb\nc\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch upstream", temp_dir.path())?;
 
-        fs::write(
-            target_dir.join("patches/0001-update-line1.patch"),
-            "diff --git a/a.txt b/a.txt
-index 83db48f..efc6926 100644
---- a/a.txt
-+++ b/a.txt
-@@ -1,3 +1,3 @@
--line999
-+line123
- line2
- line3
-",
+        update(
+            UpdateCommandArgs {
+                version: Some("v2".to_string()),
+                path: Some("//third_party/upstream".parse()?),
+                ..Default::default()
+            },
+            &paths,
         )?;
-        commit_code("Create patch1", &target_dir)?;
 
-        // make all patches pending
-        metadata.update_state = Some(UpdateState {
-            prev_commit_hash: get_current_commit(&paths.root)?,
-            new_version: "12345".to_string(),
-            patches: load_patch_list(&target_dir)?
-                .iter()
-                .map(|e| PatchApplyState {
-                    name: e.clone(),
-                    state: PatchState::Pending,
-                })
-                .collect(),
-        });
-        update_metadata(&target_dir, &metadata)?;
+        assert_eq!(list_files(&repo_dir)?, vec![PathBuf::from("src/a.c")]);
+        assert_eq!(
+            fs::read_to_string(repo_dir.join("src/a.c"))?,
+            "patched\nb\nc\nd\n"
+        );
+        assert!(load_metadata(&paths, &target_dir)?.update_state.is_none());
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
 
-        let apply_result = update(
-            UpdateCommandArgs {
-                version: None,
-                force: false,
-                status: false,
-                cont: true,
-                abort: false,
-                path: Some("//third_party/example".to_string()),
+        Ok(())
+    }
+
+    #[test]
+    fn test_vendor_and_update_archive() -> anyhow::Result<()> {
+        let archives_dir = tempdir()?;
+        for (version, content) in [
+            ("1.0", "line1\nline2\nline3\n"),
+            ("2.0", "line1\nline2\nline3\nline4\n"),
+        ] {
+            let pkg_dir = archives_dir.path().join(format!("pkg-{version}"));
+            fs::create_dir_all(pkg_dir.join("src"))?;
+            fs::write(pkg_dir.join("a.txt"), content)?;
+            fs::write(pkg_dir.join("src/lib.c"), version)?;
+        }
+        let mut tar_cmd = Command::new("tar");
+        tar_cmd
+            .current_dir(archives_dir.path())
+            .args(["-czf", "pkg-1.0.tar.gz", "pkg-1.0"]);
+        run_command(tar_cmd, "tar", None)?;
+        let mut zip_cmd = Command::new("zip");
+        zip_cmd
+            .current_dir(archives_dir.path())
+            .args(["-qr", "pkg-2.0.zip", "pkg-2.0"]);
+        run_command(zip_cmd, "zip", None)?;
+        let archive_v1 = archives_dir.path().join("pkg-1.0.tar.gz");
+        let archive_v2 = archives_dir.path().join("pkg-2.0.zip");
+
+        let temp_dir = create_test_dir()?;
+        fs::write(temp_dir.path().join(".keep"), "")?;
+        commit_code("Initial commit", temp_dir.path())?;
+
+        let paths = MonorepoPaths::from_dir(temp_dir.path())?;
+        let target_dir = path_to_abs(&paths, "//third_party/pkg")?;
+
+        let res = vendor(
+            VendorCommandArgs {
+                archive: Some(archive_v1.to_string_lossy().to_string()),
+                sha256: Some("0".repeat(64)),
+                path: "//third_party/pkg".parse()?,
+                ..Default::default()
             },
             &paths,
         );
-        assert!(
-            apply_result.is_err(),
-            "Expected Err, but got {:?}",
-            apply_result
+        assert!(res.is_err(), "Expected Err, but got {:?}", res);
+        assert!(!target_dir.exists());
+
+        let sha256_v1 = hash_file(&archive_v1)?;
+        vendor(
+            VendorCommandArgs {
+                archive: Some(archive_v1.to_string_lossy().to_string()),
+                sha256: Some(sha256_v1.clone()),
+                strip_components: Some(1),
+                path: "//third_party/pkg".parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor pkg", temp_dir.path())?;
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/src/lib.c"))?,
+            "1.0"
+        );
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert_eq!(
+            metadata.archive,
+            Some(ArchiveSource {
+                sha256: sha256_v1.clone(),
+                strip_components: 1,
+            })
+        );
+        assert_eq!(
+            metadata.revision.resolved_commit,
+            format!("sha256:{sha256_v1}")
         );
 
+        fs::write(target_dir.join("repo/a.txt"), "line123\nline2\nline3\n")?;
+        extract_patch(
+            ExtractPatchCommandArgs {
+                path: Some("//third_party/pkg".parse()?),
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Patch pkg", temp_dir.path())?;
+
         update(
             UpdateCommandArgs {
-                version: None,
-                force: false,
-                status: false,
-                cont: false,
-                abort: true,
-                path: Some("//third_party/example".to_string()),
+                archive: Some(archive_v2.to_string_lossy().to_string()),
+                sha256: Some(hash_file(&archive_v2)?),
+                path: Some("//third_party/pkg".parse()?),
+                ..Default::default()
             },
             &paths,
         )?;
-        let new_metadata = load_metadata(&target_dir)?;
-        assert!(
-            new_metadata.update_state.is_none(),
-            "Expected None, but got {:?}",
-            new_metadata.update_state
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/src/lib.c"))?,
+            "2.0"
         );
-        assert_eq!(new_metadata.version, "default".to_string());
+        assert_eq!(
+            fs::read_to_string(target_dir.join("repo/a.txt"))?,
+            "line123\nline2\nline3\nline4\n"
+        );
+        let metadata = load_metadata(&paths, &target_dir)?;
+        assert!(metadata.url.ends_with("pkg-2.0.zip"));
+        assert!(metadata.update_state.is_none());
+
+        assert_eq!(verify_dependency(&paths, &target_dir)?, vec![]);
 
         Ok(())
     }
 
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("v1.10", "v1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("v1.2", "v1.2"), Ordering::Equal);
+        assert_eq!(compare_versions("v1.2", "v1.2.1"), Ordering::Less);
+        assert_eq!(compare_versions("v2.0", "v10.0"), Ordering::Less);
+        assert_eq!(compare_versions("v1.2-rc1", "v1.2"), Ordering::Less);
+        assert_eq!(compare_versions("v1.2", "v1.2-beta"), Ordering::Greater);
+        assert_eq!(compare_versions("v1.2-rc2", "v1.2-rc1"), Ordering::Greater);
+        assert_eq!(compare_versions("v1.2-rc1", "v1.1"), Ordering::Greater);
+    }
+
     #[test]
     fn integration_vendor_and_patch_test() -> anyhow::Result<()> {
         let temp_dir = create_test_dir()?;
 
         fs::write(temp_dir.path().join(".keep"), "")?;
         fs::create_dir_all(temp_dir.path().join("third_party"))?;
-        commit_code("Initial commit", &temp_dir.path())?;
+        commit_code("Initial commit", temp_dir.path())?;
 
         let paths = MonorepoPaths::from_dir(temp_dir.path())?;
 
         // Vendor third-party dep
         vendor(
             VendorCommandArgs {
-                git: "https://github.com/khamutov/dockyard.git".to_string(),
+                git: Some("https://github.com/khamutov/dockyard.git".to_string()),
                 version: Some("879bfd9".to_string()),
-                path: "//third_party/dockyard".to_string(),
+                path: "//third_party/dockyard".parse()?,
+                ..Default::default()
             },
             &paths,
         )?;
-        commit_code("Vendor dockyard", &temp_dir.path())?;
+        commit_code("Vendor dockyard", temp_dir.path())?;
 
         // Edit Cargo.toml
         fs::write(
@@ -1191,7 +6672,8 @@ serde_json = "1.0"
         )?;
         extract_patch(
             ExtractPatchCommandArgs {
-                path: "//third_party/dockyard".to_string(),
+                path: Some("//third_party/dockyard".parse()?),
+                ..Default::default()
             },
             &paths,
         )?;
@@ -1202,11 +6684,8 @@ serde_json = "1.0"
         update(
             UpdateCommandArgs {
                 version: Some("a784ec0".to_string()),
-                force: false,
-                status: false,
-                cont: false,
-                abort: false,
-                path: Some("//third_party/dockyard".to_string()),
+                path: Some("//third_party/dockyard".parse()?),
+                ..Default::default()
             },
             &paths,
         )?;
@@ -1222,10 +6701,11 @@ serde_json = "1.0"
         assert_eq!(cargo_toml_content[1], "name = \"dockyard-vendored\"");
 
         let metadata: DependencyMetadata =
-            load_metadata(&temp_dir.path().join("third_party/dockyard"))?;
+            load_metadata(&paths, &temp_dir.path().join("third_party/dockyard"))?;
 
         // Check the version was updated in metadata
-        assert_eq!(metadata.version, "a784ec0");
+        assert_eq!(metadata.revision.requested_ref.as_deref(), Some("a784ec0"));
+        assert!(metadata.revision.resolved_commit.starts_with("a784ec0"));
 
         Ok(())
     }
@@ -1244,6 +6724,103 @@ serde_json = "1.0"
         Ok(temp_dir)
     }
 
+    /// Creates upstream repo with tags `v1` (lightweight), `v2` (annotated),
+    /// branch `release` pointing to `v2` and one more commit on top of them
+    /// in the default branch.
+    fn create_upstream_repo() -> anyhow::Result<TempDir> {
+        let upstream_dir = tempdir()?;
+        init_git(upstream_dir.path())?;
+
+        for version in ["v1", "v2", "v3"] {
+            fs::write(upstream_dir.path().join("a.txt"), format!("{version}\n"))?;
+            commit_code(&format!("Release {version}"), upstream_dir.path())?;
+            match version {
+                "v1" => run_git(upstream_dir.path(), &["tag", version])?,
+                "v2" => {
+                    run_git(upstream_dir.path(), &["tag", "-a", "-m", version, version])?;
+                    run_git(upstream_dir.path(), &["branch", "release"])?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(upstream_dir)
+    }
+
+    /// Creates a monorepo with the upstream repo vendored at `v1` into
+    /// `//third_party/upstream` and a patch extracted and committed for every
+    /// `(file, content, message)`, the message is the patch title optionally
+    /// followed by a description. Returns the upstream dir, the monorepo dir
+    /// and its paths.
+    fn vendor_with_patches(
+        patches: &[(&str, &str, &str)],
+    ) -> anyhow::Result<(TempDir, TempDir, MonorepoPaths)> {
+        let upstream_dir = create_upstream_repo()?;
+        let temp_dir = create_test_dir()?;
+        let root = temp_dir.path();
+        let paths = MonorepoPaths::from_dir(root)?;
+        fs::write(root.join(".keep"), "")?;
+        commit_code("Initial commit", root)?;
+
+        let canonical_path = "//third_party/upstream";
+        let target_dir = path_to_abs(&paths, canonical_path)?;
+        vendor(
+            VendorCommandArgs {
+                git: Some(upstream_dir.path().to_string_lossy().to_string()),
+                version: Some("v1".to_string()),
+                path: canonical_path.parse()?,
+                ..Default::default()
+            },
+            &paths,
+        )?;
+        commit_code("Vendor upstream", root)?;
+        for (file, content, message) in patches {
+            fs::write(target_dir.join("repo").join(file), content)?;
+            run_git(root, &["add", "."])?;
+            extract_patch(
+                ExtractPatchCommandArgs {
+                    path: Some(canonical_path.parse()?),
+                    message: Some(message.to_string()),
+                    ..Default::default()
+                },
+                &paths,
+            )?;
+            commit_code(message.lines().next().unwrap_or_default(), root)?;
+        }
+
+        Ok((upstream_dir, temp_dir, paths))
+    }
+
+    fn get_commit(current_dir: &Path, rev: &str) -> anyhow::Result<String> {
+        let rev_parse_cmd = Command::new("git")
+            .current_dir(current_dir)
+            .arg("rev-parse")
+            .args(rev.split_whitespace())
+            .output()?;
+        if !rev_parse_cmd.status.success() {
+            bail!("git rev-parse {} failed", rev);
+        }
+        Ok(String::from_utf8(rev_parse_cmd.stdout)?.trim().to_string())
+    }
+
+    fn run_git(current_dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+        let git_cmd = Command::new("git")
+            .current_dir(current_dir)
+            .args(args)
+            .output()?;
+
+        if !git_cmd.status.success() {
+            bail!(
+                "git {:?} failed, stdout: {}, stderr: {}",
+                args,
+                String::from_utf8_lossy(&git_cmd.stdout),
+                String::from_utf8_lossy(&git_cmd.stderr),
+            );
+        }
+
+        Ok(())
+    }
+
     fn init_git(current_dir: &Path) -> anyhow::Result<()> {
         let commit_cmd = Command::new("git")
             .current_dir(current_dir)